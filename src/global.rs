@@ -0,0 +1,2112 @@
+//! A process-wide MDC shared by all threads.
+//!
+//! The functions in this module mirror the thread local API at the crate root, but operate on a
+//! single map protected by a lock. Every modification of the map bumps a generation counter, which
+//! allows callers to detect concurrent updates.
+//!
+//! # Sharding
+//!
+//! With the `sharded` Cargo feature enabled, entries are partitioned by key hash across a fixed
+//! number of shards, each protected by its own lock, so that writes to different keys can proceed
+//! in parallel. Operations on a single key only lock that key's shard, while operations on the
+//! whole map, such as `iter` or `snapshot_versioned`, lock every shard. To avoid deadlocks, shards
+//! are always locked in the same order, and no operation holding a single shard's lock acquires
+//! another one.
+//!
+//! # Flags
+//!
+//! With the `arc-swap` Cargo feature enabled, the `flag` function provides handles to values which
+//! are stored outside of the main map and can be read and written without taking any lock. This is
+//! intended for a small number of frequently read, rarely written values.
+//!
+//! # Poisoning
+//!
+//! If a thread panics while holding a write lock on the global MDC, the lock is poisoned. Rather
+//! than propagating the panic to every later user of the global MDC, the poisoning is ignored and
+//! the map is used as-is. The `was_poisoned` function reports whether this has happened.
+//!
+//! ```
+//! use std::panic;
+//!
+//! use log_mdc::global;
+//!
+//! let entries = vec![("a", "1"), ("b", "2")];
+//! let result = panic::catch_unwind(|| {
+//!     global::extend(entries.into_iter().map(|(k, v)| {
+//!         if k == "b" {
+//!             panic!("failed to compute the entry");
+//!         }
+//!         (k, v)
+//!     }));
+//! });
+//! assert!(result.is_err());
+//!
+//! // the entry inserted before the panic is kept
+//! assert_eq!(Some("1".to_owned()), global::remove("a"));
+//! global::insert("c", "3");
+//! assert!(global::was_poisoned());
+//! assert_eq!(1, global::high_water_mark());
+//! ```
+use std::borrow::Borrow;
+use std::cell::Cell;
+#[cfg(feature = "read-cache")]
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::{ControlFlow, Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{self, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockResult};
+use std::time::SystemTime;
+#[cfg(any(feature = "arc-swap", feature = "arc-values"))]
+use std::sync::Arc;
+#[cfg(feature = "arc-values")]
+use std::sync::Weak;
+
+#[cfg(feature = "arc-swap")]
+use arc_swap::ArcSwapOption;
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// An error returned when the lock on the global MDC could not be acquired without blocking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryLockError(());
+
+impl fmt::Display for TryLockError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("the global MDC lock is currently held")
+    }
+}
+
+impl Error for TryLockError {}
+
+/// An error returned when parsing the line format read by `load` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    line: usize,
+    msg: String,
+}
+
+impl ParseError {
+    fn new(line: usize, msg: String) -> ParseError {
+        ParseError {
+            line,
+            msg,
+        }
+    }
+
+    /// Returns the 1-based number of the line which failed to parse.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for ParseError {}
+
+/// A hierarchical key into the global MDC.
+///
+/// A key is built from a sequence of segments which are joined with `Key::SEPARATOR`, so that all
+/// namespaced keys agree on a single separator. A key converts into the plain string key used by
+/// the rest of this module, so entries inserted through either API are visible to both.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global::{self, Key};
+///
+/// let key = Key::new(["http", "request", "method"]);
+/// assert_eq!("http.request.method", key.as_str());
+///
+/// global::insert_key(&key, "GET");
+/// global::get("http.request.method", |v| assert_eq!(Some("GET"), v));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key(String);
+
+impl Key {
+    /// The separator placed between segments.
+    pub const SEPARATOR: char = '.';
+
+    /// Creates a key from its segments.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if a segment contains `Key::SEPARATOR`.
+    pub fn new<I, S>(segments: I) -> Key
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        let mut key = Key(String::new());
+        for (i, segment) in segments.into_iter().enumerate() {
+            if i > 0 {
+                key.0.push(Key::SEPARATOR);
+            }
+            key.push_segment(segment.as_ref());
+        }
+        key
+    }
+
+    /// Returns a new key with `segment` appended to this one.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if `segment` contains `Key::SEPARATOR`.
+    pub fn child(&self, segment: &str) -> Key {
+        let mut key = self.clone();
+        key.0.push(Key::SEPARATOR);
+        key.push_segment(segment);
+        key
+    }
+
+    /// Returns an iterator over the segments of the key.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split(Key::SEPARATOR)
+    }
+
+    /// Returns the string form of the key.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Determines if `key` is this key or one nested under it.
+    fn covers(&self, key: &str) -> bool {
+        key.starts_with(&self.0) &&
+        (key.len() == self.0.len() || key[self.0.len()..].starts_with(Key::SEPARATOR))
+    }
+
+    fn push_segment(&mut self, segment: &str) {
+        debug_assert!(!segment.contains(Key::SEPARATOR),
+                      "key segment `{}` contains the separator",
+                      segment);
+        self.0.push_str(segment);
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.0)
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> String {
+        key.0
+    }
+}
+
+/// A serializable copy of the entries in the global MDC, returned by `snapshot_serializable`.
+///
+/// The entries are sorted by key, and serialize as a map from keys to values.
+///
+/// Requires the `serde` Cargo feature.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot(Vec<(String, String)>);
+
+#[cfg(feature = "serde")]
+impl Snapshot {
+    /// Returns an iterator over the entries of the snapshot, sorted by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (&**k, &**v))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Snapshot {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Counts of write lock acquisitions on the global MDC, returned by `contention_stats`.
+///
+/// Requires the `contention-metrics` Cargo feature.
+#[cfg(feature = "contention-metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentionStats {
+    total_writes: u64,
+    contended_writes: u64,
+}
+
+#[cfg(feature = "contention-metrics")]
+impl ContentionStats {
+    /// Returns the number of blocking write lock acquisitions.
+    pub fn total_writes(&self) -> u64 {
+        self.total_writes
+    }
+
+    /// Returns the number of blocking write lock acquisitions which had to wait for the lock.
+    pub fn contended_writes(&self) -> u64 {
+        self.contended_writes
+    }
+}
+
+/// The kind of modification recorded by a `Transition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionKind {
+    /// An entry was inserted by `insert`.
+    Insert,
+    /// An entry was removed by `remove`.
+    Remove,
+    /// Every entry was removed by `clear`.
+    Clear,
+}
+
+/// A modification of the global MDC recorded in the transition log.
+///
+/// See `enable_transition_log` for details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    kind: TransitionKind,
+    key: Option<String>,
+    old_value: Option<String>,
+    new_value: Option<String>,
+    timestamp: SystemTime,
+}
+
+impl Transition {
+    /// Returns the kind of the modification.
+    pub fn kind(&self) -> TransitionKind {
+        self.kind
+    }
+
+    /// Returns the key of the modified entry, or `None` for a `Clear`.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Returns the value of the entry before the modification, or `None` if it was absent or for a
+    /// `Clear`.
+    pub fn old_value(&self) -> Option<&str> {
+        self.old_value.as_deref()
+    }
+
+    /// Returns the value of the entry after the modification, which is only present for an
+    /// `Insert`.
+    pub fn new_value(&self) -> Option<&str> {
+        self.new_value.as_deref()
+    }
+
+    /// Returns the time at which the modification was made.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+}
+
+struct TransitionLog {
+    capacity: usize,
+    entries: VecDeque<Transition>,
+}
+
+#[cfg(not(feature = "arc-values"))]
+type Value = String;
+#[cfg(feature = "arc-values")]
+type Value = Arc<str>;
+
+type Map = HashMap<String, Value>;
+
+#[cfg(not(feature = "arc-values"))]
+fn into_value(value: String) -> Value {
+    value
+}
+
+#[cfg(feature = "arc-values")]
+fn into_value(value: String) -> Value {
+    Arc::from(value)
+}
+
+#[cfg(not(feature = "arc-values"))]
+fn from_value(value: Value) -> String {
+    value
+}
+
+#[cfg(feature = "arc-values")]
+fn from_value(value: Value) -> String {
+    String::from(&*value)
+}
+
+#[cfg(not(feature = "sharded"))]
+const SHARDS: usize = 1;
+#[cfg(feature = "sharded")]
+const SHARDS: usize = 16;
+
+lazy_static! {
+    static ref MDC: Vec<RwLock<Map>> = (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect();
+    // the tag which last inserted each key through insert_owned_by, locked after the shards
+    static ref OWNERS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    // serializes calls to with_isolated
+    static ref ISOLATION: Mutex<()> = Mutex::new(());
+    // locked after the shards
+    static ref TRANSITIONS: Mutex<TransitionLog> = Mutex::new(TransitionLog {
+        capacity: 0,
+        entries: VecDeque::new(),
+    });
+}
+
+// entries added by insert_weak, locked after the shards
+#[cfg(feature = "arc-values")]
+lazy_static! {
+    static ref WEAK: RwLock<HashMap<String, Weak<str>>> = RwLock::new(HashMap::new());
+}
+
+#[cfg(feature = "arc-swap")]
+lazy_static! {
+    static ref FLAGS: RwLock<HashMap<String, Arc<ArcSwapOption<String>>>> =
+        RwLock::new(HashMap::new());
+}
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+// the number of entries in each shard, only written with that shard locked
+static LENS: [AtomicUsize; SHARDS] = [const { AtomicUsize::new(0) }; SHARDS];
+static HIGH_WATER_MARK: AtomicUsize = AtomicUsize::new(0);
+static POISONED: AtomicBool = AtomicBool::new(false);
+static AUTO_INHERIT: AtomicBool = AtomicBool::new(false);
+static TRANSITIONS_ENABLED: AtomicBool = AtomicBool::new(false);
+// set once insert_owned_by has been called, so OWNERS needn't be locked before then
+static OWNED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "contention-metrics")]
+static TOTAL_WRITES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "contention-metrics")]
+static CONTENDED_WRITES: AtomicU64 = AtomicU64::new(0);
+
+// a copy of the global MDC and the generation it was taken at
+#[cfg(feature = "read-cache")]
+thread_local!(static CACHE: RefCell<Option<(u64, HashMap<String, String>)>> =
+    const { RefCell::new(None) });
+
+#[cfg(not(feature = "sharded"))]
+fn shard_index<Q>(_: &Q) -> usize
+    where Q: ?Sized + Hash
+{
+    0
+}
+
+#[cfg(feature = "sharded")]
+fn shard_index<Q>(key: &Q) -> usize
+    where Q: ?Sized + Hash
+{
+    // DefaultHasher::new always uses the same keys, so a key is always routed to the same shard
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// Guards over every shard of the global MDC, acquired in index order.
+struct Shards<G>(Vec<G>);
+
+impl<G> Shards<G>
+    where G: Deref<Target = Map>
+{
+    fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().flat_map(|m| m.iter()).map(|(k, v)| (&**k, &**v))
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.iter().map(|m| m.capacity()).sum()
+    }
+
+    fn to_map(&self) -> HashMap<String, String> {
+        self.iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+}
+
+impl<G> Shards<G>
+    where G: DerefMut<Target = Map>
+{
+    fn insert(&mut self, key: String, value: String) -> Option<String> {
+        let index = shard_index(&*key);
+        disown(&key);
+        self.0[index].insert(key, into_value(value)).map(from_value)
+    }
+
+    fn extend<I>(&mut self, entries: I)
+        where I: IntoIterator<Item = (String, String)>
+    {
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        disown(key);
+        self.0[shard_index(key)].remove(key).map(from_value)
+    }
+
+    fn clear(&mut self) {
+        disown_where(|_| true);
+        for m in &mut self.0 {
+            m.clear();
+        }
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&str) -> bool
+    {
+        disown_where(|k| !f(k));
+        for m in &mut self.0 {
+            m.retain(|k, _| f(k));
+        }
+    }
+
+    // replaces every entry, keeping the owners of entries whose values are unchanged
+    fn replace(&mut self, new: HashMap<String, String>) {
+        disown_where(|k| {
+            let old = self.0[shard_index(k)].get(k);
+            old.map(|v| &**v) != new.get(k).map(|v| &**v)
+        });
+        // weak entries aren't part of the copies the new contents are built from
+        #[cfg(feature = "arc-values")]
+        lock(WEAK.write()).clear();
+        for m in &mut self.0 {
+            m.clear();
+        }
+        for (key, value) in new {
+            let index = shard_index(&*key);
+            self.0[index].insert(key, into_value(value));
+        }
+    }
+}
+
+fn recover<G>(e: sync::PoisonError<G>) -> G {
+    POISONED.store(true, Ordering::Relaxed);
+    e.into_inner()
+}
+
+fn lock<G>(result: sync::LockResult<G>) -> G {
+    result.unwrap_or_else(recover)
+}
+
+fn try_lock<G>(result: TryLockResult<G>) -> Option<G> {
+    match result {
+        Ok(guard) => Some(guard),
+        Err(sync::TryLockError::WouldBlock) => None,
+        Err(sync::TryLockError::Poisoned(e)) => Some(recover(e)),
+    }
+}
+
+// the number of shard locks held by the current thread
+thread_local!(static HELD: Cell<usize> = const { Cell::new(0) });
+
+/// A guard over a shard of the global MDC which records that the current thread holds its lock.
+struct Held<G> {
+    guard: G,
+    _count: HeldCount,
+}
+
+impl<G> Held<G> {
+    fn new(guard: G) -> Held<G> {
+        HELD.with(|h| h.set(h.get() + 1));
+        Held {
+            guard,
+            _count: HeldCount(()),
+        }
+    }
+}
+
+impl<G> Deref for Held<G>
+    where G: Deref<Target = Map>
+{
+    type Target = Map;
+
+    fn deref(&self) -> &Map {
+        &self.guard
+    }
+}
+
+impl<G> DerefMut for Held<G>
+    where G: DerefMut<Target = Map>
+{
+    fn deref_mut(&mut self) -> &mut Map {
+        &mut self.guard
+    }
+}
+
+// dropped after the guard, once the lock has been released
+struct HeldCount(());
+
+impl Drop for HeldCount {
+    fn drop(&mut self) {
+        HELD.with(|h| h.set(h.get() - 1));
+    }
+}
+
+type ReadGuard = Held<RwLockReadGuard<'static, Map>>;
+type WriteGuard = Held<RwLockWriteGuard<'static, Map>>;
+
+/// Determines if the current thread holds a lock on any shard of the global MDC.
+pub(crate) fn is_locked_by_current_thread() -> bool {
+    HELD.with(|h| h.get() > 0)
+}
+
+fn read() -> Shards<ReadGuard> {
+    Shards(MDC.iter().map(|s| Held::new(lock(s.read()))).collect())
+}
+
+fn read_shard<Q>(key: &Q) -> ReadGuard
+    where Q: ?Sized + Hash
+{
+    Held::new(lock(MDC[shard_index(key)].read()))
+}
+
+fn try_read_shard<Q>(key: &Q) -> Option<ReadGuard>
+    where Q: ?Sized + Hash
+{
+    try_lock(MDC[shard_index(key)].try_read()).map(Held::new)
+}
+
+fn write() -> Shards<WriteGuard> {
+    Shards(MDC.iter().map(write_lock).collect())
+}
+
+fn write_shard(index: usize) -> WriteGuard {
+    write_lock(&MDC[index])
+}
+
+#[cfg(not(feature = "contention-metrics"))]
+fn write_lock(shard: &'static RwLock<Map>) -> WriteGuard {
+    Held::new(lock(shard.write()))
+}
+
+#[cfg(feature = "contention-metrics")]
+fn write_lock(shard: &'static RwLock<Map>) -> WriteGuard {
+    TOTAL_WRITES.fetch_add(1, Ordering::Relaxed);
+    let guard = match try_lock(shard.try_write()) {
+        Some(guard) => guard,
+        None => {
+            CONTENDED_WRITES.fetch_add(1, Ordering::Relaxed);
+            lock(shard.write())
+        }
+    };
+    Held::new(guard)
+}
+
+fn try_write_shard(index: usize) -> Option<WriteGuard> {
+    try_lock(MDC[index].try_write()).map(Held::new)
+}
+
+// must be called with the key's shard locked whenever its entry is replaced or removed
+fn disown(key: &str) {
+    if OWNED.load(Ordering::Relaxed) {
+        lock(OWNERS.lock()).remove(key);
+    }
+}
+
+// must be called with every shard locked
+fn disown_where<F>(mut f: F)
+    where F: FnMut(&str) -> bool
+{
+    if OWNED.load(Ordering::Relaxed) {
+        lock(OWNERS.lock()).retain(|k, _| !f(k));
+    }
+}
+
+fn recording() -> bool {
+    TRANSITIONS_ENABLED.load(Ordering::Relaxed)
+}
+
+// must be called with the modified shards still locked so transitions are logged in order
+fn record(kind: TransitionKind,
+          key: Option<String>,
+          old_value: Option<String>,
+          new_value: Option<String>) {
+    let mut log = lock(TRANSITIONS.lock());
+    if log.capacity == 0 {
+        return;
+    }
+    if log.entries.len() == log.capacity {
+        log.entries.pop_front();
+    }
+    log.entries.push_back(Transition {
+        kind,
+        key,
+        old_value,
+        new_value,
+        timestamp: SystemTime::now(),
+    });
+}
+
+// must be called with the shards to be modified locked, before modifying them, so that the
+// generation changes even if the modification panics
+fn modifying() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+// must be called with the modified shard still locked
+//
+// Lengths are recorded absolutely rather than as deltas, so a shard which was left modified by a
+// panic is brought back in sync by its next modification.
+fn modified(index: usize, shard: &Map) {
+    LENS[index].store(shard.len(), Ordering::Relaxed);
+    HIGH_WATER_MARK.fetch_max(len(), Ordering::Relaxed);
+}
+
+// must be called with every shard still locked
+fn modified_all(m: &Shards<WriteGuard>) {
+    for (len, shard) in LENS.iter().zip(&m.0) {
+        len.store(shard.len(), Ordering::Relaxed);
+    }
+    HIGH_WATER_MARK.fetch_max(len(), Ordering::Relaxed);
+}
+
+fn len() -> usize {
+    LENS.iter().map(|l| l.load(Ordering::Relaxed)).sum()
+}
+
+fn modify<F, T>(f: F) -> T
+    where F: FnOnce(&mut Shards<WriteGuard>) -> T
+{
+    let mut m = write();
+    modifying();
+    let ret = f(&mut m);
+    modified_all(&m);
+    ret
+}
+
+fn modify_shard<F, T>(index: usize, f: F) -> T
+    where F: FnOnce(&mut Map) -> T
+{
+    let mut m = write_shard(index);
+    modifying();
+    let ret = f(&mut m);
+    modified(index, &m);
+    ret
+}
+
+/// A handle to a flag in the global MDC.
+///
+/// Flags are created by the `flag` function.
+#[cfg(feature = "arc-swap")]
+#[derive(Clone)]
+pub struct Flag(Arc<ArcSwapOption<String>>);
+
+#[cfg(feature = "arc-swap")]
+impl Flag {
+    /// Returns the current value of the flag without taking a lock.
+    pub fn load(&self) -> Option<Arc<String>> {
+        self.0.load_full()
+    }
+
+    /// Sets the value of the flag without taking a lock, returning the old value.
+    pub fn store<V>(&self, value: V) -> Option<Arc<String>>
+        where V: Into<String>
+    {
+        self.0.swap(Some(Arc::new(value.into())))
+    }
+
+    /// Removes the value of the flag without taking a lock, returning the old value.
+    pub fn clear(&self) -> Option<Arc<String>> {
+        self.0.swap(None)
+    }
+}
+
+/// Returns a handle to the flag with the specified name, creating it if it doesn't exist.
+///
+/// A flag holds a single value which is stored separately from the rest of the global MDC, so that
+/// it can be read and written without taking a lock. Flags which have a value are included in the
+/// output of `iter`, but are not otherwise visible through the functions of this module, and are
+/// independent of any entry in the map with the same name. Looking up the handle takes a lock, so
+/// it should be done once and the handle reused.
+///
+/// Requires the `arc-swap` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// let maintenance = global::flag("maintenance");
+/// assert_eq!(None, maintenance.store("true"));
+/// assert_eq!("true", &**maintenance.load().unwrap());
+///
+/// let mut seen = false;
+/// global::iter(|k, v| seen |= k == "maintenance" && v == "true");
+/// assert!(seen);
+/// ```
+#[cfg(feature = "arc-swap")]
+pub fn flag(name: &str) -> Flag {
+    if let Some(value) = lock(FLAGS.read()).get(name) {
+        return Flag(value.clone());
+    }
+
+    let value = lock(FLAGS.write()).entry(name.to_owned()).or_default().clone();
+    Flag(value)
+}
+
+/// Eagerly initializes the global MDC.
+///
+/// The global MDC is otherwise initialized lazily on first use. Calling this during startup moves
+/// that one-time cost out of latency sensitive code paths.
+pub fn init() {
+    lazy_static::initialize(&MDC);
+    drop(read());
+}
+
+/// Inserts a new entry into the global MDC, returning the old value.
+pub fn insert<K, V>(key: K, value: V) -> Option<String>
+    where K: Into<String>,
+          V: Into<String>
+{
+    let key = key.into();
+    let value = value.into();
+    modify_shard(shard_index(&*key), |m| {
+        let transition = if recording() {
+            Some((key.clone(), value.clone()))
+        } else {
+            None
+        };
+
+        disown(&key);
+        let old_value = m.insert(key, into_value(value)).map(from_value);
+        if let Some((key, value)) = transition {
+            record(TransitionKind::Insert, Some(key), old_value.clone(), Some(value));
+        }
+        old_value
+    })
+}
+
+/// Inserts a new entry into the global MDC without blocking, returning the old value.
+///
+/// Returns an error if the lock is currently held by another thread, in which case the global MDC
+/// is not modified.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// assert_eq!(Ok(None), global::try_insert("foo", "a"));
+/// assert_eq!(Some(Some("a".to_owned())), global::try_get("foo", |v| v.map(|v| v.to_owned())));
+///
+/// global::iter(|_, _| assert!(global::try_insert("foo", "b").is_err()));
+/// ```
+pub fn try_insert<K, V>(key: K, value: V) -> Result<Option<String>, TryLockError>
+    where K: Into<String>,
+          V: Into<String>
+{
+    let key = key.into();
+    let index = shard_index(&*key);
+    let mut m = try_write_shard(index).ok_or(TryLockError(()))?;
+    modifying();
+    disown(&key);
+    let old_value = m.insert(key, into_value(value.into())).map(from_value);
+    modified(index, &m);
+    Ok(old_value)
+}
+
+/// Inserts a new entry into the global MDC on behalf of an owner, returning the old value.
+///
+/// The entry is recorded as owned by `tag`, which is typically a name identifying the inserting
+/// thread, so that `clear_owned_by` can later remove it without affecting entries inserted by
+/// others. If the key was already owned by a different tag, its ownership transfers to `tag`.
+/// The entry stops being owned as soon as it is overwritten or removed through any other function
+/// of this module. Entries left unchanged by `transaction` or `commit_if_unchanged` keep their
+/// owners, and `with_isolated` restores the owners along with the entries.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// use log_mdc::global;
+///
+/// thread::spawn(|| {
+///     global::insert_owned_by("worker-1", "worker-1.status", "running");
+///     global::insert_owned_by("worker-1", "shared", "1");
+/// }).join().unwrap();
+///
+/// global::insert_owned_by("worker-2", "shared", "2");
+///
+/// global::clear_owned_by("worker-1");
+/// global::get("worker-1.status", |v| assert_eq!(None, v));
+/// global::get("shared", |v| assert_eq!(Some("2"), v));
+///
+/// global::insert_owned_by("worker-3", "retry", "1");
+/// global::remove("retry");
+/// global::insert("retry", "main");
+/// global::clear_owned_by("worker-3");
+/// global::get("retry", |v| assert_eq!(Some("main"), v));
+/// ```
+pub fn insert_owned_by<K, V>(tag: &str, key: K, value: V) -> Option<String>
+    where K: Into<String>,
+          V: Into<String>
+{
+    let key = key.into();
+    modify_shard(shard_index(&*key), |m| {
+        OWNED.store(true, Ordering::Relaxed);
+        lock(OWNERS.lock()).insert(key.clone(), tag.to_owned());
+        m.insert(key, into_value(value.into())).map(from_value)
+    })
+}
+
+/// Removes every entry in the global MDC currently owned by `tag`.
+///
+/// See `insert_owned_by` for details.
+pub fn clear_owned_by(tag: &str) {
+    modify(|m| {
+        let keys = lock(OWNERS.lock())
+            .iter()
+            .filter(|&(_, t)| t == tag)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            m.remove(&key);
+        }
+    })
+}
+
+/// Extends the global MDC with new entries.
+pub fn extend<K, V, I>(entries: I)
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    modify(|m| m.extend(entries.into_iter().map(|(k, v)| (k.into(), v.into()))));
+}
+
+/// Extends the global MDC with the entries of a map under a single write lock, consuming it.
+///
+/// Unlike `extend`, the keys and values are moved into the global MDC without any conversion.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// let saved = global::snapshot();
+///
+/// global::clear();
+/// global::extend_owned(saved);
+/// global::get("service", |v| assert_eq!(Some("web"), v));
+/// ```
+pub fn extend_owned(map: HashMap<String, String>) {
+    modify(|m| m.extend(map))
+}
+
+/// Extends the global MDC with clones of the entries of a map under a single write lock.
+pub fn extend_ref(map: &HashMap<String, String>) {
+    modify(|m| m.extend(map.iter().map(|(k, v)| (k.clone(), v.clone()))))
+}
+
+/// Retrieves a value from the global MDC.
+///
+/// If the key is present, the read lock is held while the closure runs.
+pub fn get<Q, F, T>(key: &Q, f: F) -> T
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq,
+          F: FnOnce(Option<&str>) -> T
+{
+    let m = read_shard(key);
+    if let Some(value) = m.get(key) {
+        return f(Some(value));
+    }
+    drop(m);
+
+    #[cfg(feature = "arc-values")]
+    {
+        if let Some(value) = get_weak(key) {
+            return f(Some(&value));
+        }
+    }
+
+    f(None)
+}
+
+/// Retrieves a shared reference to a value in the global MDC.
+///
+/// With the `arc-values` Cargo feature enabled, values in the global MDC are stored as `Arc<str>`,
+/// so this returns a new reference to the stored value without copying it, while functions such
+/// as `get_or_compute` which return a `String` copy it out of the `Arc`. Modifying an entry
+/// replaces its `Arc` rather than mutating the value in place, so a returned value is never
+/// changed, but remains alive after the entry is modified or removed. Weakly held entries added by
+/// `insert_weak` are also returned, as long as they are still alive.
+///
+/// Requires the `arc-values` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("config", "a large value");
+/// let config = global::get_arc("config").unwrap();
+///
+/// global::insert("config", "a new value");
+/// assert_eq!("a large value", &*config);
+/// assert_eq!("a new value", &*global::get_arc("config").unwrap());
+/// ```
+#[cfg(feature = "arc-values")]
+pub fn get_arc<Q>(key: &Q) -> Option<Arc<str>>
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    if let Some(value) = read_shard(key).get(key) {
+        return Some(value.clone());
+    }
+
+    get_weak(key)
+}
+
+#[cfg(feature = "arc-values")]
+fn get_weak<Q>(key: &Q) -> Option<Arc<str>>
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    match lock(WEAK.read()).get(key) {
+        Some(value) => {
+            if let Some(value) = value.upgrade() {
+                return Some(value);
+            }
+        }
+        None => return None,
+    }
+
+    let mut weak = lock(WEAK.write());
+    if weak.get(key).is_some_and(|v| v.strong_count() == 0) {
+        weak.remove(key);
+    }
+    None
+}
+
+/// Inserts a weakly held entry into the global MDC.
+///
+/// The global MDC only holds a `Weak` reference to the value, so it does not keep the value alive.
+/// While some other `Arc` pointing to the value exists, `get` returns it like any other entry.
+/// Once the last one is dropped, the value is freed and the entry behaves as if it had been
+/// removed: `get` returns `None` and removes the dead entry. This allows the global MDC to refer
+/// to large values which are only relevant while the scope owning them is alive.
+///
+/// Weak entries are only visible through `get`, and are removed by `remove` and `clear`, as well as
+/// when the contents of the global MDC are replaced by `transaction` or `commit_if_unchanged`.
+/// A weak entry replaces any regular entry for the key, but is itself shadowed by a regular entry
+/// which is inserted later.
+///
+/// Requires the `arc-values` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::Arc;
+///
+/// use log_mdc::global;
+///
+/// let blob: Arc<str> = Arc::from("a large diagnostic blob");
+/// global::insert_weak("blob", blob.clone());
+/// global::get("blob", |v| assert_eq!(Some("a large diagnostic blob"), v));
+///
+/// global::with_isolated(HashMap::new(), || global::get("blob", |v| assert_eq!(None, v)));
+/// global::get("blob", |v| assert_eq!(Some("a large diagnostic blob"), v));
+///
+/// global::transaction(|m| -> Result<_, ()> { Ok(m.remove("blob")) }).unwrap();
+/// global::get("blob", |v| assert_eq!(None, v));
+///
+/// drop(blob);
+/// global::get("blob", |v| assert_eq!(None, v));
+/// ```
+#[cfg(feature = "arc-values")]
+pub fn insert_weak(key: &str, value: Arc<str>) {
+    modify_shard(shard_index(key), |m| {
+        disown(key);
+        m.remove(key);
+        lock(WEAK.write()).insert(key.to_owned(), Arc::downgrade(&value));
+    })
+}
+
+/// Retrieves a copy of a value from the global MDC through a thread local cache.
+///
+/// Each thread keeps a copy of the entire global MDC along with the generation at which it was
+/// taken. If the generation of the global MDC has not changed since then, the value is returned
+/// from the copy without taking any lock. Otherwise, the copy is refreshed under a single read
+/// lock first. A returned value therefore reflects every modification which completed before the
+/// call, just like `get`, but refreshing copies every entry, so this is only a win for keys which
+/// are read far more often than the global MDC is modified.
+///
+/// Requires the `read-cache` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// assert_eq!(Some("web".to_owned()), global::cached_get("service"));
+///
+/// global::insert("service", "worker");
+/// assert_eq!(Some("worker".to_owned()), global::cached_get("service"));
+/// ```
+#[cfg(feature = "read-cache")]
+pub fn cached_get(key: &str) -> Option<String> {
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        let current = match *c {
+            Some((generation, _)) => generation == GENERATION.load(Ordering::SeqCst),
+            None => false,
+        };
+
+        if !current {
+            let m = read();
+            // writers bump the generation while holding the lock, so it can't change under us
+            *c = Some((GENERATION.load(Ordering::SeqCst), m.to_map()));
+        }
+
+        c.as_ref().unwrap().1.get(key).cloned()
+    })
+}
+
+/// Retrieves a value from the global MDC, falling back to the thread local MDC.
+///
+/// The global MDC is checked first, and the thread local MDC is only consulted if the key is
+/// absent from it. Neither MDC is modified.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// log_mdc::insert("service", "worker");
+/// log_mdc::insert("request", "1234");
+///
+/// global::get_or_local("service", |v| assert_eq!(Some("web"), v));
+/// global::get_or_local("request", |v| assert_eq!(Some("1234"), v));
+/// ```
+pub fn get_or_local<Q, F, T>(key: &Q, f: F) -> T
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq,
+          F: FnOnce(Option<&str>) -> T
+{
+    let m = read_shard(key);
+    match m.get(key) {
+        Some(value) => f(Some(value)),
+        None => {
+            drop(m);
+            super::get(key, f)
+        }
+    }
+}
+
+/// Retrieves a value from the global MDC, computing and inserting it if it is absent.
+///
+/// `compute` is called with no lock held, so it can itself read or modify the global MDC. The
+/// lookup is repeated under the write lock before inserting the computed value, so if multiple
+/// threads race to insert it, each of them may call `compute`, but the first value inserted is
+/// kept and returned to all of them.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// assert_eq!("web", global::get_or_compute("service", || "web".to_owned()));
+/// assert_eq!("web", global::get_or_compute("service", || unreachable!()));
+///
+/// global::insert("region", "us-east");
+/// let derived = global::get_or_compute("endpoint", || {
+///     global::get("region", |v| format!("{}.example.com", v.unwrap()))
+/// });
+/// assert_eq!("us-east.example.com", derived);
+/// ```
+pub fn get_or_compute<F>(key: &str, compute: F) -> String
+    where F: FnOnce() -> String
+{
+    if let Some(value) = read_shard(key).get(key) {
+        return String::from(&**value);
+    }
+
+    let value = compute();
+
+    let index = shard_index(key);
+    let mut m = write_shard(index);
+    if let Some(value) = m.get(key) {
+        return String::from(&**value);
+    }
+
+    modifying();
+    m.insert(key.to_owned(), into_value(value.clone()));
+    modified(index, &m);
+    value
+}
+
+/// Retrieves a value from the global MDC without blocking.
+///
+/// Returns `None` if the lock is currently held by a writer. This is distinct from the key being
+/// absent, in which case the closure is invoked with `None`.
+pub fn try_get<Q, F, T>(key: &Q, f: F) -> Option<T>
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq,
+          F: FnOnce(Option<&str>) -> T
+{
+    try_read_shard(key).map(|m| f(m.get(key).map(|v| &**v)))
+}
+
+/// Determines if any entry in the global MDC has the specified value.
+///
+/// This scans every entry under the read lock, so it takes time linear in the size of the MDC.
+pub fn contains_value(value: &str) -> bool {
+    read().iter().any(|(_, v)| v == value)
+}
+
+/// Removes a value from the global MDC.
+pub fn remove<Q>(key: &Q) -> Option<String>
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    modify_shard(shard_index(key), |m| {
+        #[cfg(feature = "arc-values")]
+        lock(WEAK.write()).remove(key);
+        let (key, old_value) = m.remove_entry(key)?;
+        disown(&key);
+        let old_value = from_value(old_value);
+        if recording() {
+            record(TransitionKind::Remove, Some(key), Some(old_value.clone()), None);
+        }
+        Some(old_value)
+    })
+}
+
+/// Removes a value from the global MDC, returning whether it was present.
+pub fn remove_bool<Q>(key: &Q) -> bool
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    remove(key).is_some()
+}
+
+/// Removes all values from the global MDC.
+pub fn clear() {
+    modify(|m| {
+        #[cfg(feature = "arc-values")]
+        lock(WEAK.write()).clear();
+        m.clear();
+        if recording() {
+            record(TransitionKind::Clear, None, None, None);
+        }
+    })
+}
+
+/// Atomically replaces all entries in the global MDC whose keys start with `prefix`.
+///
+/// Existing entries under the prefix are removed and `entries` are inserted under a single write
+/// lock, so concurrent readers never observe a partially updated namespace. Entries outside of the
+/// prefix are not affected, so the keys in `entries` must already carry the prefix.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use log_mdc::global;
+///
+/// global::insert("config.a", "1");
+/// global::insert("config.b", "2");
+/// global::insert("service", "web");
+///
+/// let mut config = HashMap::new();
+/// config.insert("config.c".to_owned(), "3".to_owned());
+/// global::replace_prefixed("config.", config);
+///
+/// global::get("config.a", |v| assert_eq!(None, v));
+/// global::get("config.c", |v| assert_eq!(Some("3"), v));
+/// global::get("service", |v| assert_eq!(Some("web"), v));
+/// ```
+pub fn replace_prefixed(prefix: &str, entries: HashMap<String, String>) {
+    debug_assert!(entries.keys().all(|k| k.starts_with(prefix)),
+                  "replacement entries must start with the prefix `{}`",
+                  prefix);
+
+    modify(|m| {
+        m.retain(|k| !k.starts_with(prefix));
+        m.extend(entries);
+    })
+}
+
+/// Atomically adds `delta` to the integer value of an entry in the global MDC, returning the new
+/// value.
+///
+/// A missing entry, or one whose value cannot be parsed as an `i64`, is treated as 0. The addition
+/// wraps on overflow.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// assert_eq!(1, global::increment("requests", 1));
+/// assert_eq!(3, global::increment("requests", 2));
+///
+/// global::insert("name", "foo");
+/// assert_eq!(-1, global::increment("name", -1));
+/// ```
+pub fn increment(key: &str, delta: i64) -> i64 {
+    modify_shard(shard_index(key), |m| {
+        let value = m.get(key)
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(0)
+            .wrapping_add(delta);
+        disown(key);
+        m.insert(key.to_owned(), into_value(value.to_string()));
+        value
+    })
+}
+
+/// Inserts a new entry with a hierarchical key into the global MDC, returning the old value.
+pub fn insert_key<V>(key: &Key, value: V) -> Option<String>
+    where V: Into<String>
+{
+    insert(key.as_str(), value)
+}
+
+/// Retrieves a value with a hierarchical key from the global MDC.
+///
+/// The read lock is held while the closure runs.
+pub fn get_key<F, T>(key: &Key, f: F) -> T
+    where F: FnOnce(Option<&str>) -> T
+{
+    get(key.as_str(), f)
+}
+
+/// Removes a value with a hierarchical key from the global MDC.
+pub fn remove_key(key: &Key) -> Option<String> {
+    remove(key.as_str())
+}
+
+/// Removes the entry for `key` and every entry nested under it from the global MDC.
+///
+/// Only whole segments are matched, so removing `http.request` does not affect
+/// `http.requests`.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global::{self, Key};
+///
+/// let request = Key::new(["http", "request"]);
+/// global::insert_key(&request.child("method"), "GET");
+/// global::insert_key(&request.child("path"), "/");
+/// global::insert("http.requests", "10");
+///
+/// global::remove_under(&request);
+///
+/// global::get("http.request.method", |v| assert_eq!(None, v));
+/// global::get("http.request.path", |v| assert_eq!(None, v));
+/// global::get("http.requests", |v| assert_eq!(Some("10"), v));
+/// ```
+pub fn remove_under(key: &Key) {
+    modify(|m| m.retain(|k| !key.covers(k)))
+}
+
+/// Invokes the provided closure for each entry in the global MDC.
+///
+/// The read lock is held for the duration of the iteration. With the `arc-swap` Cargo feature
+/// enabled, flags which currently have a value are visited after the entries of the map.
+pub fn iter<F>(mut f: F)
+    where F: FnMut(&str, &str)
+{
+    try_iter(|k, v| {
+        f(k, v);
+        ControlFlow::<()>::Continue(())
+    });
+}
+
+/// Invokes the provided closure for each entry in the global MDC whose key satisfies a predicate.
+///
+/// The read lock is held for the duration of the iteration, as with `iter`.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::extend(vec![("http.method", "GET"), ("http.path", "/"), ("service", "web")]);
+///
+/// let mut count = 0;
+/// global::iter_filtered(|k| k.starts_with("http."), |_, _| count += 1);
+/// assert_eq!(2, count);
+/// ```
+pub fn iter_filtered<P, F>(mut pred: P, mut f: F)
+    where P: FnMut(&str) -> bool,
+          F: FnMut(&str, &str)
+{
+    iter(|k, v| {
+        if pred(k) {
+            f(k, v)
+        }
+    })
+}
+
+/// Invokes the provided closure for each entry in the global MDC until it returns
+/// `ControlFlow::Break`, returning the value it broke with.
+///
+/// The read lock is held only until the iteration stops, so it is released as soon as the closure
+/// breaks. Returns `None` if the closure never breaks.
+///
+/// # Examples
+///
+/// ```
+/// use std::ops::ControlFlow;
+///
+/// use log_mdc::global;
+///
+/// global::extend(vec![("a", "1"), ("b", "2"), ("c", "3")]);
+///
+/// let mut visited = 0;
+/// let found = global::try_iter(|k, _| {
+///     visited += 1;
+///     if k == "a" || k == "b" {
+///         ControlFlow::Break(k.to_owned())
+///     } else {
+///         ControlFlow::Continue(())
+///     }
+/// });
+///
+/// assert!(found.is_some());
+/// assert!(visited < 3);
+///
+/// // the read lock has been released
+/// assert!(global::try_insert("d", "4").is_ok());
+/// ```
+pub fn try_iter<F, B>(mut f: F) -> Option<B>
+    where F: FnMut(&str, &str) -> ControlFlow<B>
+{
+    for (key, value) in read().iter() {
+        if let ControlFlow::Break(b) = f(key, value) {
+            return Some(b);
+        }
+    }
+
+    #[cfg(feature = "arc-swap")]
+    for (name, value) in lock(FLAGS.read()).iter() {
+        if let Some(value) = &*value.load() {
+            if let ControlFlow::Break(b) = f(name, value) {
+                return Some(b);
+            }
+        }
+    }
+
+    None
+}
+
+/// Invokes the provided closure with all of the entries in the global MDC at once.
+///
+/// The read lock is held while the closure runs. As with `iter`, flags which currently have a
+/// value are included after the entries of the map if the `arc-swap` Cargo feature is enabled.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::extend(vec![("a", "1"), ("b", "2")]);
+///
+/// let max = global::with_all(|entries| entries.iter().map(|&(_, v)| v).max().map(str::to_owned));
+/// assert_eq!(Some("2".to_owned()), max);
+/// ```
+pub fn with_all<F, R>(f: F) -> R
+    where F: FnOnce(&[(&str, &str)]) -> R
+{
+    let m = read();
+    #[cfg_attr(not(feature = "arc-swap"), allow(unused_mut))]
+    let mut entries = m.iter().collect::<Vec<_>>();
+
+    #[cfg(feature = "arc-swap")]
+    let flags = lock(FLAGS.read())
+        .iter()
+        .filter_map(|(k, v)| v.load_full().map(|v| (k.clone(), v)))
+        .collect::<Vec<_>>();
+    #[cfg(feature = "arc-swap")]
+    entries.extend(flags.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+
+    f(&entries)
+}
+
+/// Returns a copy of the entries in the global MDC.
+///
+/// The read lock is held only while the entries are copied.
+pub fn snapshot() -> HashMap<String, String> {
+    read().to_map()
+}
+
+/// Returns a copy of the entries in the global MDC, sorted by key.
+pub fn snapshot_sorted() -> Vec<(String, String)> {
+    let mut entries = read().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect::<Vec<_>>();
+    entries.sort();
+    entries
+}
+
+/// Returns a serializable copy of the entries in the global MDC.
+///
+/// The read lock is held only while the entries are copied, not while the snapshot is serialized.
+///
+/// Requires the `serde` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// extern crate log_mdc;
+/// extern crate serde_json;
+///
+/// use log_mdc::global;
+///
+/// fn main() {
+///     global::insert("service", "web");
+///     global::insert("region", "us-east");
+///
+///     let json = serde_json::to_string(&global::snapshot_serializable()).unwrap();
+///     assert_eq!(r#"{"region":"us-east","service":"web"}"#, json);
+/// }
+/// ```
+#[cfg(feature = "serde")]
+pub fn snapshot_serializable() -> Snapshot {
+    Snapshot(snapshot_sorted())
+}
+
+/// Returns the entries in the global MDC as a JSON object, sorted by key.
+///
+/// The read lock is held only while the entries are copied, not while they are formatted.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// global::insert("path", "/a \"b\"");
+///
+/// assert_eq!(r#"{"path":"/a \"b\"","service":"web"}"#, global::to_json_string());
+/// ```
+pub fn to_json_string() -> String {
+    let mut out = String::from("{");
+    for (i, (key, value)) in snapshot_sorted().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        escape_json(key, &mut out);
+        out.push(':');
+        escape_json(value, &mut out);
+    }
+    out.push('}');
+    out
+}
+
+/// Returns a fingerprint of the contents of the global MDC.
+///
+/// The fingerprint is computed from the entries sorted by key, so two MDCs with the same contents
+/// have the same fingerprint regardless of the order in which their entries were inserted. It is
+/// stable within a process, but not necessarily across Rust versions.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("a", "1");
+/// global::insert("b", "2");
+/// let fingerprint = global::fingerprint();
+///
+/// global::clear();
+/// global::insert("b", "2");
+/// global::insert("a", "1");
+/// assert_eq!(fingerprint, global::fingerprint());
+///
+/// global::insert("a", "3");
+/// assert!(fingerprint != global::fingerprint());
+/// ```
+pub fn fingerprint() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (key, value) in snapshot_sorted() {
+        key.hash(&mut hasher);
+        value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Returns copies of the keys and values in the global MDC as two parallel vectors.
+///
+/// The value at each index of the second vector corresponds to the key at the same index of the
+/// first.
+pub fn snapshot_columns() -> (Vec<String>, Vec<String>) {
+    read().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).unzip()
+}
+
+/// Replaces the contents of `dst` with a copy of the entries in the global MDC.
+///
+/// `dst` is cleared first, so its allocation can be reused when repeatedly snapshotting the MDC.
+pub fn snapshot_into(dst: &mut HashMap<String, String>) {
+    dst.clear();
+    dst.extend(read().iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
+}
+
+/// Returns a copy of the global MDC to seed a worker thread's MDC.
+///
+/// This is the first half of the fork/join pattern: each worker passes the returned map to the
+/// thread local `extend`, and contributes its results back with `join` when it finishes.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::thread;
+///
+/// use log_mdc::global;
+///
+/// global::insert("job", "import");
+///
+/// let workers = (0..4)
+///     .map(|i| {
+///         let base = global::fork();
+///         thread::spawn(move || {
+///             log_mdc::extend(base);
+///
+///             let job = log_mdc::get("job", |v| v.unwrap().to_owned());
+///
+///             let mut results = HashMap::new();
+///             results.insert(format!("worker.{}", i), job);
+///             global::join(results);
+///         })
+///     })
+///     .collect::<Vec<_>>();
+///
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// global::get("worker.3", |v| assert_eq!(Some("import"), v));
+/// ```
+pub fn fork() -> HashMap<String, String> {
+    read().to_map()
+}
+
+/// Merges a worker's results into the global MDC under a single write lock.
+///
+/// This is the second half of the fork/join pattern described in `fork`.
+pub fn join(results: HashMap<String, String>) {
+    modify(|m| m.extend(results))
+}
+
+/// Invokes the provided closure with the contents of the global MDC temporarily replaced by `map`.
+///
+/// The old contents are restored when the closure returns or panics. This is intended for tests of
+/// code which uses the global MDC, which would otherwise observe entries left behind by other
+/// tests. Calls to this function are serialized by a lock, so tests which use it will not run
+/// concurrently with each other even when the test harness runs tests in parallel. Code outside
+/// of the closures is not serialized, however, and may still observe the temporary contents. A
+/// nested call from within the closure will deadlock.
+///
+/// Along with the entries, the weak entries added by `insert_weak`, the ownership records of
+/// `insert_owned_by` and the peak reported by `high_water_mark` are replaced for the duration of
+/// the closure and restored afterwards. The generation, the transition log, the flags returned
+/// by `flag` and the poison flag are shared with the rest of the process.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+///
+/// let mut map = HashMap::new();
+/// map.insert("service".to_owned(), "test".to_owned());
+/// global::with_isolated(map, || {
+///     global::get("service", |v| assert_eq!(Some("test"), v));
+///     global::extend(vec![("a", "1"), ("b", "2")]);
+///     assert_eq!(3, global::high_water_mark());
+/// });
+///
+/// global::get("service", |v| assert_eq!(Some("web"), v));
+/// assert_eq!(1, global::high_water_mark());
+///
+/// global::insert_owned_by("w1", "w1.status", "running");
+/// global::with_isolated(HashMap::new(), || ());
+/// global::clear_owned_by("w1");
+/// global::get("w1.status", |v| assert_eq!(None, v));
+/// ```
+pub fn with_isolated<F, R>(map: HashMap<String, String>, f: F) -> R
+    where F: FnOnce() -> R
+{
+    struct Saved {
+        entries: HashMap<String, String>,
+        owners: HashMap<String, String>,
+        #[cfg(feature = "arc-values")]
+        weak: HashMap<String, Weak<str>>,
+        high_water_mark: usize,
+    }
+
+    struct Restore(Option<Saved>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let saved = self.0.take().unwrap();
+            modify(|m| {
+                m.clear();
+                m.extend(saved.entries);
+                *lock(OWNERS.lock()) = saved.owners;
+                #[cfg(feature = "arc-values")]
+                {
+                    *lock(WEAK.write()) = saved.weak;
+                }
+                HIGH_WATER_MARK.store(saved.high_water_mark, Ordering::Relaxed);
+            })
+        }
+    }
+
+    // a panic in another closure doesn't invalidate anything protected by the lock
+    let _lock = ISOLATION.lock().unwrap_or_else(sync::PoisonError::into_inner);
+    let saved = modify(|m| {
+        let saved = Saved {
+            entries: m.to_map(),
+            owners: mem::take(&mut *lock(OWNERS.lock())),
+            #[cfg(feature = "arc-values")]
+            weak: mem::take(&mut *lock(WEAK.write())),
+            high_water_mark: HIGH_WATER_MARK.load(Ordering::Relaxed),
+        };
+        m.clear();
+        m.extend(map);
+        // the peak is raised to the length of the new contents once the closure returns
+        HIGH_WATER_MARK.store(0, Ordering::Relaxed);
+        saved
+    });
+    let _restore = Restore(Some(saved));
+    f()
+}
+
+/// Returns the capacity of the map backing the global MDC.
+pub fn capacity() -> usize {
+    read().capacity()
+}
+
+/// Returns the current generation of the global MDC along with a copy of its contents.
+///
+/// The generation can be passed to `commit_if_unchanged` to replace the contents only if no other
+/// modification has happened in the meantime.
+///
+/// # Examples
+///
+/// A retry loop which increments a counter without losing concurrent updates:
+///
+/// ```
+/// use log_mdc::global;
+///
+/// loop {
+///     let (version, mut mdc) = global::snapshot_versioned();
+///     let count = mdc.get("count").map_or(0, |v| v.parse::<u64>().unwrap()) + 1;
+///     mdc.insert("count".to_owned(), count.to_string());
+///
+///     if global::commit_if_unchanged(version, mdc) {
+///         break;
+///     }
+/// }
+///
+/// global::get("count", |v| assert_eq!(Some("1"), v));
+/// ```
+pub fn snapshot_versioned() -> (u64, HashMap<String, String>) {
+    let m = read();
+    (GENERATION.load(Ordering::SeqCst), m.to_map())
+}
+
+/// Replaces the contents of the global MDC if its generation still matches `version`.
+///
+/// Returns `true` if the contents were replaced, and `false` if the global MDC was modified since
+/// `version` was obtained from `snapshot_versioned`.
+pub fn commit_if_unchanged(version: u64, new: HashMap<String, String>) -> bool {
+    let mut m = write();
+    if GENERATION.load(Ordering::SeqCst) != version {
+        return false;
+    }
+
+    modifying();
+    m.replace(new);
+    modified_all(&m);
+    true
+}
+
+/// Applies a batch of changes to the global MDC, keeping them only if the closure succeeds.
+///
+/// The closure is invoked with a copy of the entries in the global MDC, taken under the read lock.
+/// If it returns `Ok`, the contents of the global MDC are replaced with the modified copy under a
+/// single write lock. If it returns `Err`, the global MDC is left unchanged and the error is
+/// returned. No lock is held while the closure runs, so any modifications made by other threads in
+/// the meantime are overwritten when the changes are committed. Use `snapshot_versioned` and
+/// `commit_if_unchanged` instead if they must be detected. Every call copies the entire global
+/// MDC, so this is best suited to infrequent updates.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("db.host", "a.example.com");
+/// global::insert("db.port", "5432");
+///
+/// let result = global::transaction(|m| {
+///     m.insert("db.host".to_owned(), "b.example.com".to_owned());
+///     let port = "not a port".parse::<u16>().map_err(|_| "invalid port")?;
+///     m.insert("db.port".to_owned(), port.to_string());
+///     Ok(())
+/// });
+///
+/// assert_eq!(Err("invalid port"), result);
+/// global::get("db.host", |v| assert_eq!(Some("a.example.com"), v));
+/// ```
+pub fn transaction<F, R, E>(f: F) -> Result<R, E>
+    where F: FnOnce(&mut HashMap<String, String>) -> Result<R, E>
+{
+    let mut new = snapshot();
+    let ret = f(&mut new)?;
+
+    modify(|m| m.replace(new));
+    Ok(ret)
+}
+
+/// Returns counts of the write lock acquisitions on the global MDC since the process started.
+///
+/// A write is considered contended if an attempt to acquire the lock without blocking fails before
+/// falling back to waiting for it, so the counts are an approximation. With the `sharded` Cargo
+/// feature enabled, each shard locked by a write is counted separately. Writes made through
+/// non-blocking functions such as `try_insert` are not counted.
+///
+/// Requires the `contention-metrics` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// let before = global::contention_stats();
+/// global::insert("foo", "a");
+///
+/// let after = global::contention_stats();
+/// assert!(after.total_writes() > before.total_writes());
+/// ```
+#[cfg(feature = "contention-metrics")]
+pub fn contention_stats() -> ContentionStats {
+    ContentionStats {
+        total_writes: TOTAL_WRITES.load(Ordering::Relaxed),
+        contended_writes: CONTENDED_WRITES.load(Ordering::Relaxed),
+    }
+}
+
+/// Enables recording of the modifications made to the global MDC in a bounded transition log.
+///
+/// Once enabled, each call to `insert`, `remove` or `clear` is recorded as a `Transition` with a
+/// timestamp. Removals of absent keys are not recorded, and modifications made through any other
+/// function are not recorded either. Only the most recent `capacity` transitions are kept, with
+/// older ones discarded as new ones are recorded. The log is disabled by default. Calling this
+/// again changes the capacity, discarding the oldest transitions if necessary, and a capacity of 0
+/// disables the log.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global::{self, TransitionKind};
+///
+/// global::enable_transition_log(2);
+/// global::insert("state", "starting");
+/// global::insert("state", "running");
+/// global::remove("state");
+///
+/// let log = global::transition_log();
+/// assert_eq!(2, log.len());
+/// assert_eq!(TransitionKind::Insert, log[0].kind());
+/// assert_eq!(Some("starting"), log[0].old_value());
+/// assert_eq!(Some("running"), log[0].new_value());
+/// assert_eq!(TransitionKind::Remove, log[1].kind());
+/// ```
+pub fn enable_transition_log(capacity: usize) {
+    let mut log = lock(TRANSITIONS.lock());
+    log.capacity = capacity;
+    while log.entries.len() > capacity {
+        log.entries.pop_front();
+    }
+    log.entries.shrink_to(capacity);
+    TRANSITIONS_ENABLED.store(capacity > 0, Ordering::Relaxed);
+}
+
+/// Returns a copy of the transitions recorded in the transition log, oldest first.
+///
+/// See `enable_transition_log` for details.
+pub fn transition_log() -> Vec<Transition> {
+    lock(TRANSITIONS.lock()).entries.iter().cloned().collect()
+}
+
+/// Returns the largest number of entries the global MDC has held.
+///
+/// The peak is tracked from process start, or from the last call to `reset_high_water_mark`.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::extend(vec![("a", "1"), ("b", "2")]);
+/// global::remove("a");
+/// assert_eq!(2, global::high_water_mark());
+///
+/// global::reset_high_water_mark();
+/// assert_eq!(1, global::high_water_mark());
+/// ```
+pub fn high_water_mark() -> usize {
+    HIGH_WATER_MARK.load(Ordering::Relaxed)
+}
+
+/// Resets the peak number of entries tracked by `high_water_mark` to the current number of entries.
+pub fn reset_high_water_mark() {
+    // writers update the lengths and the peak under the write lock, so can't race with the store
+    let _m = read();
+    HIGH_WATER_MARK.store(len(), Ordering::Relaxed);
+}
+
+/// Determines if a poisoned lock on the global MDC has been recovered from.
+///
+/// This indicates that a thread panicked while modifying the global MDC, and that a later access
+/// used the map anyway. The flag is tracked from process start, or from the last call to
+/// `clear_poison_flag`.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// use log_mdc::global;
+///
+/// let entries = (0..1).map(|_| -> (String, String) { panic!() });
+/// thread::spawn(move || global::extend(entries)).join().unwrap_err();
+///
+/// global::insert("foo", "a");
+/// global::get("foo", |v| assert_eq!(Some("a"), v));
+/// assert!(global::was_poisoned());
+///
+/// global::clear_poison_flag();
+/// assert!(!global::was_poisoned());
+///
+/// global::get("foo", |v| assert_eq!(Some("a"), v));
+/// assert!(!global::was_poisoned());
+/// ```
+pub fn was_poisoned() -> bool {
+    POISONED.load(Ordering::Relaxed)
+}
+
+/// Resets the flag reported by `was_poisoned`.
+///
+/// The poisoned locks are marked as healthy again, so the flag is only set by later panics.
+pub fn clear_poison_flag() {
+    for shard in MDC.iter() {
+        shard.clear_poison();
+    }
+    OWNERS.clear_poison();
+    TRANSITIONS.clear_poison();
+    #[cfg(feature = "arc-values")]
+    WEAK.clear_poison();
+    #[cfg(feature = "arc-swap")]
+    FLAGS.clear_poison();
+    POISONED.store(false, Ordering::Relaxed);
+}
+
+/// Serializes the contents of the global MDC into a line-based text format.
+///
+/// Each entry is written on its own line as the key and value separated by a tab, with entries
+/// sorted by key. Backslashes, tabs, carriage returns and newlines in keys and values are escaped
+/// as `\\`, `\t`, `\r` and `\n` respectively, so any contents survive a round trip through
+/// `load`.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// global::insert("message", "tab\tnewline\nbackslash\\");
+/// global::insert("unicode", "héllo wörld ✓");
+///
+/// let dump = global::dump();
+/// assert_eq!(2, dump.lines().count());
+///
+/// let before = global::snapshot_sorted();
+/// global::clear();
+/// global::load(&dump).unwrap();
+/// assert_eq!(before, global::snapshot_sorted());
+/// ```
+pub fn dump() -> String {
+    let mut out = String::new();
+    for (key, value) in snapshot_sorted() {
+        escape(&key, &mut out);
+        out.push('\t');
+        escape(&value, &mut out);
+        out.push('\n');
+    }
+    out
+}
+
+/// Extends the global MDC with entries parsed from the format written by `dump`.
+///
+/// Empty lines are ignored. If any line fails to parse, an error is returned and the global MDC is
+/// not modified.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::global;
+///
+/// let err = global::load("foo\ta\nbar\n").unwrap_err();
+/// assert_eq!(2, err.line());
+/// global::get("foo", |v| assert_eq!(None, v));
+/// ```
+pub fn load(s: &str) -> Result<(), ParseError> {
+    let mut entries = vec![];
+    for (i, line) in s.split('\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.split('\t');
+        let (key, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(key), Some(value), None) => (key, value),
+            _ => {
+                let msg = "expected a key and value separated by a tab".to_owned();
+                return Err(ParseError::new(i + 1, msg));
+            }
+        };
+        entries.push((unescape(key, i + 1)?, unescape(value, i + 1)?));
+    }
+
+    extend(entries);
+    Ok(())
+}
+
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_json(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn unescape(s: &str, line: usize) -> Result<String, ParseError> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => {
+                let msg = format!("invalid escape sequence `\\{}`", c);
+                return Err(ParseError::new(line, msg));
+            }
+            None => return Err(ParseError::new(line, "unterminated escape sequence".to_owned())),
+        }
+    }
+    Ok(out)
+}
+
+/// Enables or disables seeding new thread local MDCs from the global MDC.
+///
+/// When enabled, each thread's MDC is extended with a copy of the global MDC when it is created,
+/// which happens the first time the thread uses it. This acquires the global read lock once per
+/// thread. Later changes to the global MDC are not propagated to MDCs which already exist.
+///
+/// If a thread first uses its MDC while it holds a lock on the global MDC, for example from
+/// within the closure passed to `iter`, acquiring the lock again could deadlock. In that case,
+/// seeding is deferred to the first use of the thread's MDC after the lock is released, and
+/// entries inserted into the thread's MDC in the meantime take precedence over the global ones.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// global::set_auto_inherit(true);
+///
+/// thread::spawn(|| {
+///     log_mdc::get("service", |v| assert_eq!(Some("web"), v));
+/// }).join().unwrap();
+/// ```
+///
+/// Copying the global MDC into the thread's MDC from within `iter` does not deadlock, even while
+/// another thread is waiting to write to the global MDC:
+///
+/// ```
+/// use std::sync::mpsc;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use log_mdc::global;
+///
+/// global::insert("service", "web");
+/// global::set_auto_inherit(true);
+///
+/// let (tx, rx) = mpsc::channel();
+/// let writer = thread::spawn(move || {
+///     rx.recv().unwrap();
+///     global::insert("request", "1234");
+/// });
+///
+/// thread::spawn(move || {
+///     global::iter(|k, v| {
+///         // give the writer time to block on the lock
+///         tx.send(()).unwrap();
+///         thread::sleep(Duration::from_millis(100));
+///         log_mdc::insert(k, v);
+///     });
+///     log_mdc::get("service", |v| assert_eq!(Some("web"), v));
+/// }).join().unwrap();
+///
+/// writer.join().unwrap();
+/// ```
+pub fn set_auto_inherit(enabled: bool) {
+    AUTO_INHERIT.store(enabled, Ordering::Relaxed);
+}
+
+// The entries to seed a new thread local MDC with, if auto inheritance is enabled.
+pub(crate) fn inherited() -> Option<HashMap<String, String>> {
+    if AUTO_INHERIT.load(Ordering::Relaxed) {
+        Some(read().to_map())
+    } else {
+        None
+    }
+}
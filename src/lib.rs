@@ -8,6 +8,9 @@
 //! different threads. Generating an ID for each request and storing it in the
 //! MDC makes it easy to partition log messages on a per-request basis.
 //!
+//! A process-wide MDC shared between all threads is available in the [`global`](global/index.html)
+//! module.
+//!
 //! # Examples
 //!
 //! Forwarding the contents of the MDC to a new thread:
@@ -27,19 +30,303 @@
 #![doc(html_root_url="https://sfackler.github.io/rust-log-mdc/doc/v0.1.0")]
 #![warn(missing_docs)]
 
+#[cfg(feature = "arc-swap")]
+extern crate arc_swap;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+
+use std::any;
 use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::hash::Hash;
+use std::mem;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+#[cfg(feature = "log")]
+use std::time::Instant;
+
+use map::{Map, Slot};
+
+pub mod global;
+mod map;
+
+/// An error returned when decoding the binary format read by `from_bytes` fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.0)
+    }
+}
+
+impl Error for DecodeError {}
+
+/// An error returned when a key is rejected by the validator installed with `set_key_validator`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidKey(String);
+
+impl InvalidKey {
+    /// Returns the rejected key.
+    pub fn key(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "invalid MDC key `{}`", self.0)
+    }
+}
+
+impl Error for InvalidKey {}
+
+type KeyValidator = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+lazy_static! {
+    static ref KEY_VALIDATOR: RwLock<Option<KeyValidator>> = RwLock::new(None);
+}
+
+// allows skipping the lock when no validator is installed
+static HAS_KEY_VALIDATOR: AtomicBool = AtomicBool::new(false);
+static STRICT_KEY_VALIDATION: AtomicBool = AtomicBool::new(false);
+
+thread_local!(static INITIAL_CAPACITY: Cell<usize> = const { Cell::new(0) });
+
+thread_local!(static MDC: RefCell<Map> = {
+    let mut map = Map::with_capacity(INITIAL_CAPACITY.with(|c| c.get()));
+    if global::is_locked_by_current_thread() {
+        // reading the global MDC could deadlock, so seed the MDC from it on a later access
+        SEED_PENDING.with(|p| p.set(true));
+    } else if let Some(entries) = global::inherited() {
+        map.extend(entries);
+    }
+    RefCell::new(map)
+});
+
+thread_local!(static SEED_PENDING: Cell<bool> = const { Cell::new(false) });
+
+thread_local!(static GENERATION: Cell<u64> = const { Cell::new(0) });
+
+fn with_mdc<F, T>(f: F) -> T
+    where F: FnOnce(&RefCell<Map>) -> T
+{
+    MDC.with(|m| {
+        if SEED_PENDING.with(|p| p.get()) && !global::is_locked_by_current_thread() {
+            if let Ok(mut m) = m.try_borrow_mut() {
+                SEED_PENDING.with(|p| p.set(false));
+                if let Some(entries) = global::inherited() {
+                    // entries inserted before seeding take precedence
+                    for (key, value) in entries {
+                        if m.get(&*key).is_none() {
+                            m.insert(key, value);
+                        }
+                    }
+                    GENERATION.with(|g| g.set(g.get().wrapping_add(1)));
+                }
+            }
+        }
+        f(m)
+    })
+}
+
+fn with_mut<F, T>(f: F) -> T
+    where F: FnOnce(&mut Map) -> T
+{
+    let ret = with_mdc(|m| f(&mut m.borrow_mut()));
+    GENERATION.with(|g| g.set(g.get().wrapping_add(1)));
+    ret
+}
+
+thread_local!(static STICKY_KEYS: RefCell<HashSet<String>> = RefCell::new(HashSet::new()));
+
+// the number of outstanding guards holding each key
+thread_local!(static SCOPED_KEYS: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new()));
+
+// the old values of spans which have not yet ended, by token
+thread_local!(static SPANS: RefCell<HashMap<u64, (String, Option<Slot>)>> =
+    RefCell::new(HashMap::new()));
+thread_local!(static NEXT_SPAN: Cell<u64> = const { Cell::new(0) });
+
+// the number of outstanding suppressions of each key, by target
+thread_local!(static SUPPRESSED: RefCell<HashMap<String, HashMap<String, usize>>> =
+    RefCell::new(HashMap::new()));
+
+fn track_scoped(key: &str) {
+    SCOPED_KEYS.with(|s| *s.borrow_mut().entry(key.to_owned()).or_insert(0) += 1);
+}
+
+fn untrack_scoped(key: &str) {
+    SCOPED_KEYS.with(|s| {
+        let mut s = s.borrow_mut();
+        let done = match s.get_mut(key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if done {
+            s.remove(key);
+        }
+    })
+}
+
+fn is_scoped(key: &str) -> bool {
+    SCOPED_KEYS.with(|s| s.borrow().contains_key(key))
+}
+
+/// Sets the initial capacity of this thread's MDC.
+///
+/// The MDC is created lazily the first time it is used on a thread, so this must be called before
+/// any other function accessing the MDC on the current thread. Otherwise, it has no effect.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::init_capacity(32);
+/// assert!(log_mdc::capacity() >= 32);
+/// ```
+pub fn init_capacity(n: usize) {
+    INITIAL_CAPACITY.with(|c| c.set(n));
+}
+
+/// Returns the generation of this thread's MDC.
+///
+/// The generation changes every time the MDC is modified, so it can be used to cache values
+/// derived from the MDC, such as a formatted representation of its entries.
+///
+/// # Examples
+///
+/// ```
+/// let generation = log_mdc::generation();
+/// log_mdc::get("foo", |_| ());
+/// assert_eq!(generation, log_mdc::generation());
+///
+/// log_mdc::insert("foo", "a");
+/// assert!(generation != log_mdc::generation());
+/// ```
+pub fn generation() -> u64 {
+    GENERATION.with(|g| g.get())
+}
+
+/// Installs a validator which is applied to the keys passed to `insert`, `extend` and
+/// `extend_reporting`.
+///
+/// Keys for which the validator returns `false` are skipped, leaving the MDC unchanged, or cause a
+/// panic if strict validation has been enabled with `set_strict_key_validation`. With the `log`
+/// Cargo feature enabled, skipped keys are also logged as warnings. `try_insert` can be used by
+/// callers who want to handle rejected keys themselves. The validator is shared by every thread
+/// and replaces any previously installed one. By default, no validation is performed.
+///
+/// The validator is not applied by the scoped insertion functions such as `insert_scoped`.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::set_key_validator(|k| !k.contains(' '));
+///
+/// log_mdc::insert("user.id", "1234");
+/// log_mdc::insert("user id", "1234");
+///
+/// log_mdc::get("user.id", |v| assert_eq!(Some("1234"), v));
+/// log_mdc::get("user id", |v| assert_eq!(None, v));
+/// assert_eq!("user id", log_mdc::try_insert("user id", "1234").unwrap_err().key());
+/// ```
+pub fn set_key_validator<F>(f: F)
+    where F: Fn(&str) -> bool + Send + Sync + 'static
+{
+    *KEY_VALIDATOR.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+    HAS_KEY_VALIDATOR.store(true, Ordering::Release);
+}
 
-thread_local!(static MDC: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new()));
+/// Sets whether keys rejected by the validator installed with `set_key_validator` cause a panic
+/// rather than being skipped.
+pub fn set_strict_key_validation(strict: bool) {
+    STRICT_KEY_VALIDATION.store(strict, Ordering::Relaxed);
+}
+
+fn key_is_valid(key: &str) -> bool {
+    if !HAS_KEY_VALIDATOR.load(Ordering::Acquire) {
+        return true;
+    }
+
+    match *KEY_VALIDATOR.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(ref validator) => validator(key),
+        None => true,
+    }
+}
+
+// applies the validation policy for functions which skip invalid keys
+fn check_key(key: &str) -> bool {
+    if key_is_valid(key) {
+        return true;
+    }
+
+    if STRICT_KEY_VALIDATION.load(Ordering::Relaxed) {
+        panic!("invalid MDC key `{}`", key);
+    }
+    #[cfg(feature = "log")]
+    warn!("skipping invalid MDC key `{}`", key);
+    false
+}
 
 /// Inserts a new entry into the MDC, returning the old value.
+///
+/// If the key is rejected by the validator installed with `set_key_validator`, the MDC is not
+/// modified and `None` is returned.
 pub fn insert<K, V>(key: K, value: V) -> Option<String>
     where K: Into<String>,
           V: Into<String>
 {
-    MDC.with(|m| m.borrow_mut().insert(key.into(), value.into()))
+    let key = key.into();
+    if !check_key(&key) {
+        return None;
+    }
+
+    with_mut(|m| m.insert(key, value.into()))
+}
+
+/// Inserts a new entry into the MDC, returning the old value, or an error if the key is rejected
+/// by the validator installed with `set_key_validator`.
+///
+/// Unlike `insert`, a rejected key never causes a panic, even if strict validation is enabled.
+pub fn try_insert<K, V>(key: K, value: V) -> Result<Option<String>, InvalidKey>
+    where K: Into<String>,
+          V: Into<String>
+{
+    let key = key.into();
+    if !key_is_valid(&key) {
+        return Err(InvalidKey(key));
+    }
+
+    Ok(with_mut(|m| m.insert(key, value.into())))
+}
+
+/// Inserts a new key-value pair into the MDC, returning the old value.
+///
+/// # Examples
+///
+/// ```
+/// let pair = ("user".to_owned(), "sfackler".to_owned());
+/// log_mdc::insert_pair(pair);
+///
+/// log_mdc::get("user", |v| assert_eq!(Some("sfackler"), v));
+/// ```
+pub fn insert_pair<P>(pair: P) -> Option<String>
+    where P: Into<(String, String)>
+{
+    let (key, value) = pair.into();
+    insert(key, value)
 }
 
 /// Inserts a new entry into the MDC in a scoped fashion.
@@ -71,129 +358,1341 @@ pub fn insert_scoped<K, V>(key: K, value: V) -> InsertGuard
           V: Into<String>
 {
     let key = key.into();
-    let old_value = insert(&*key, value);
+    let old_value = with_mut(|m| m.insert_slot(key.clone(), value.into()));
 
-    InsertGuard {
-        key: Some(key),
-        old_value: old_value,
-    }
+    InsertGuard::new(key, old_value)
 }
 
-/// Extends the MDC with new entries.
-pub fn extend<K, V, I>(entries: I)
+/// Inserts a new entry into the MDC in a scoped fashion, warning if the key already has a value.
+///
+/// This behaves like `insert_scoped`, but logs a warning through the `log` crate if the key was
+/// already present, which usually indicates that a field name is being unintentionally reused. The
+/// old value is still restored when the guard falls out of scope.
+///
+/// Requires the `log` Cargo feature.
+#[cfg(feature = "log")]
+pub fn insert_scoped_expect_absent<K, V>(key: K, value: V) -> InsertGuard
     where K: Into<String>,
-          V: Into<String>,
-          I: IntoIterator<Item = (K, V)>
+          V: Into<String>
 {
-    MDC.with(|m| m.borrow_mut().extend(entries.into_iter().map(|(k, v)| (k.into(), v.into()))));
+    let guard = insert_scoped(key, value);
+
+    if let Some(ref old_value) = guard.old_value {
+        warn!("MDC key `{}` was already set to `{}`",
+              guard.key.as_ref().unwrap(),
+              map::latest(old_value));
+    }
+
+    guard
 }
 
-/// Extends the MDC with new entries in a scoped fashion.
+/// Modifies an entry in the MDC in a scoped fashion.
 ///
-/// When the returned guard falls out of scope, it will restore the old values corresponding to the
-/// keys.
+/// The closure is passed the current value of the key (if any) and returns its new value, or
+/// `None` to remove it. When the returned guard falls out of scope, it will restore the original
+/// value corresponding to the key. With the `multimap` Cargo feature, the new value replaces every
+/// value of the key rather than being appended to them.
 ///
 /// # Examples
 ///
 /// ```
-/// log_mdc::insert("foo", "a");
+/// log_mdc::insert("request", "1234");
 ///
-/// let entries = [
-///     ("foo", "b"),
-///     ("fizz", "buzz"),
-/// ];
-///
-/// let guard = log_mdc::extend_scoped(entries.iter().cloned());
-/// log_mdc::get("foo", |v| assert_eq!(Some("b"), v));
-/// log_mdc::get("fizz", |v| assert_eq!(Some("buzz"), v));
+/// let guard = log_mdc::modify_scoped("request", |v| v.map(|v| format!("{}.db", v)));
+/// log_mdc::get("request", |v| assert_eq!(Some("1234.db"), v));
 ///
 /// drop(guard);
-/// log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
-/// log_mdc::get("fizz", |v| assert_eq!(None, v));
+/// log_mdc::get("request", |v| assert_eq!(Some("1234"), v));
 /// ```
-pub fn extend_scoped<K, V, I>(entries: I) -> ExtendGuard
-    where K: Into<String>,
-          V: Into<String>,
-          I: IntoIterator<Item = (K, V)>
+pub fn modify_scoped<Q, F>(key: &Q, f: F) -> InsertGuard
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+          F: FnOnce(Option<&str>) -> Option<String>
 {
-    MDC.with(|m| {
-        let mut m = m.borrow_mut();
-
-        let old_entries = entries.into_iter()
-            .map(|(k, v)| (k.into(), v.into()))
-            .map(|(k, v)| {
-                let v = m.insert(k.clone(), v);
-                (k, v)
-            })
-            .collect();
+    with_mut(|m| {
+        let old_value = match f(m.get(key)) {
+            Some(value) => m.replace_slot(key.to_owned(), value),
+            None => m.remove_slot(key),
+        };
 
-        ExtendGuard(old_entries)
+        InsertGuard::new(key.to_owned(), old_value)
     })
 }
 
-/// Retrieves a value from the MDC.
-pub fn get<Q: ?Sized, F, T>(key: &Q, f: F) -> T
-    where String: Borrow<Q>,
-          Q: Hash + Eq,
-          F: FnOnce(Option<&str>) -> T
+/// Inserts an entry into the MDC which logs how long it was alive when it falls out of scope.
+///
+/// The key is set to `started` until the returned guard is dropped, at which point a message of
+/// the form `{key} took {elapsed}` is logged at the debug level and the key is restored to the
+/// value it had before the call, or removed if it had none. The entry is scoped just like one
+/// inserted by `insert_scoped`.
+///
+/// Requires the `log` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// let guard = log_mdc::timed_scope("load_config");
+/// log_mdc::get("load_config", |v| assert_eq!(Some("started"), v));
+///
+/// drop(guard);
+/// log_mdc::get("load_config", |v| assert_eq!(None, v));
+///
+/// log_mdc::insert("load_config", "pending");
+/// drop(log_mdc::timed_scope("load_config"));
+/// log_mdc::get("load_config", |v| assert_eq!(Some("pending"), v));
+/// ```
+#[cfg(feature = "log")]
+pub fn timed_scope(key: &str) -> TimedGuard {
+    TimedGuard {
+        key: key.to_owned(),
+        start: Instant::now(),
+        _guard: insert_scoped(key, "started"),
+    }
+}
+
+/// Inserts a new entry into the MDC in a scoped fashion, keyed by a type.
+///
+/// The key is the name of the type `T` as returned by `std::any::type_name`, such as
+/// `my_crate::RequestId`, so distinct types defined in different modules or crates produce
+/// distinct keys, avoiding collisions between string keys chosen independently. The exact names
+/// are not guaranteed to be stable across compiler versions, so appenders shouldn't hardcode them.
+/// When the returned guard falls out of scope, it will restore the old value.
+///
+/// # Examples
+///
+/// ```
+/// struct RequestId;
+///
+/// let guard = log_mdc::insert_typed_scoped::<RequestId>("1234");
+/// assert_eq!(Some("1234".to_owned()), log_mdc::get_typed::<RequestId>());
+///
+/// drop(guard);
+/// assert_eq!(None, log_mdc::get_typed::<RequestId>());
+/// ```
+pub fn insert_typed_scoped<T>(value: impl Into<String>) -> InsertGuard
+    where T: ?Sized + 'static
 {
-    MDC.with(|m| f(m.borrow().get(key).map(|v| &**v)))
+    insert_scoped(any::type_name::<T>(), value)
 }
 
-/// Removes a value from the MDC.
-pub fn remove<Q: ?Sized>(key: &Q) -> Option<String>
-    where String: Borrow<Q>,
-          Q: Hash + Eq
+/// Returns a copy of a value inserted by `insert_typed_scoped` from the MDC.
+///
+/// Unlike `get`, this returns a copy rather than passing the value to a closure, since the
+/// closure's return type would be a second generic parameter and callers could no longer write
+/// just `get_typed::<T>()`.
+pub fn get_typed<T>() -> Option<String>
+    where T: ?Sized + 'static
 {
-    MDC.with(|m| m.borrow_mut().remove(key))
+    get(any::type_name::<T>(), |v| v.map(str::to_owned))
 }
 
-/// Removes all values from the MDC.
-pub fn clear() {
-    MDC.with(|m| m.borrow_mut().clear())
+/// The MDC key used by `operation_scope`.
+pub const OPERATION_KEY: &str = "operation";
+
+/// Sets the current operation in the MDC in a scoped fashion.
+///
+/// This is equivalent to `insert_scoped(OPERATION_KEY, name)`, but standardizes the key name so
+/// that appenders can rely on it. For example, an appender may route messages based on the value
+/// it retrieves with `log_mdc::get(log_mdc::OPERATION_KEY, ...)`.
+///
+/// # Examples
+///
+/// ```
+/// let guard = log_mdc::operation_scope("checkout");
+/// log_mdc::get(log_mdc::OPERATION_KEY, |v| assert_eq!(Some("checkout"), v));
+///
+/// drop(guard);
+/// log_mdc::get(log_mdc::OPERATION_KEY, |v| assert_eq!(None, v));
+/// ```
+pub fn operation_scope(name: &str) -> InsertGuard {
+    insert_scoped(OPERATION_KEY, name)
 }
 
-/// Invokes the provided closure for each entry in the MDC.
-pub fn iter<F>(mut f: F)
-    where F: FnMut(&str, &str)
-{
-    MDC.with(|m| {
-        for (key, value) in m.borrow().iter() {
-            f(key, value)
+/// The MDC key used by `breadcrumb_scope`.
+pub const LOCATION_KEY: &str = "location";
+
+/// Records the caller's source location in the MDC in a scoped fashion.
+///
+/// The location is formatted as `file:line`. If the key is already set, for example by an
+/// enclosing breadcrumb scope, the location is appended to the existing value separated by ` > `,
+/// so nested scopes produce a trail like `src/main.rs:10 > src/db.rs:42`. When the returned guard
+/// falls out of scope, it will restore the old value.
+///
+/// # Examples
+///
+/// ```
+/// let (outer, outer_line) = (log_mdc::breadcrumb_scope(), line!());
+/// let (inner, inner_line) = (log_mdc::breadcrumb_scope(), line!());
+///
+/// log_mdc::get(log_mdc::LOCATION_KEY, |v| {
+///     let locations = v.unwrap().split(" > ").collect::<Vec<_>>();
+///     assert_eq!(2, locations.len());
+///     assert!(locations[0].ends_with(&format!(":{}", outer_line)));
+///     assert!(locations[1].ends_with(&format!(":{}", inner_line)));
+/// });
+///
+/// drop(inner);
+/// drop(outer);
+/// log_mdc::get(log_mdc::LOCATION_KEY, |v| assert_eq!(None, v));
+/// ```
+#[track_caller]
+pub fn breadcrumb_scope() -> InsertGuard {
+    let caller = Location::caller();
+    let location = format!("{}:{}", caller.file(), caller.line());
+
+    modify_scoped(LOCATION_KEY, |v| {
+        match v {
+            Some(v) => Some(format!("{} > {}", v, location)),
+            None => Some(location),
         }
     })
 }
 
-/// A guard object which restores an MDC entry when dropped.
-pub struct InsertGuard {
-    key: Option<String>,
-    old_value: Option<String>,
+/// The MDC key used by `push_path`.
+pub const PATH_KEY: &str = "path";
+
+/// Appends a segment to the dotted path in the MDC in a scoped fashion.
+///
+/// If the key is already set, for example by an enclosing path scope, `.segment` is appended to
+/// the existing value, so nested scopes produce a path like `handler.auth.db`. Otherwise, the key
+/// is set to `segment`. When the returned guard falls out of scope, including while unwinding
+/// from a panic, it will restore the old path.
+///
+/// # Examples
+///
+/// ```
+/// let handler = log_mdc::push_path("handler");
+/// let auth = log_mdc::push_path("auth");
+/// log_mdc::get(log_mdc::PATH_KEY, |v| assert_eq!(Some("handler.auth"), v));
+///
+/// drop(auth);
+/// let db = log_mdc::push_path("db");
+/// log_mdc::get(log_mdc::PATH_KEY, |v| assert_eq!(Some("handler.db"), v));
+///
+/// drop(db);
+/// drop(handler);
+/// log_mdc::get(log_mdc::PATH_KEY, |v| assert_eq!(None, v));
+/// ```
+pub fn push_path(segment: &str) -> PathGuard {
+    let guard = modify_scoped(PATH_KEY, |v| {
+        match v {
+            Some(v) => Some(format!("{}.{}", v, segment)),
+            None => Some(segment.to_owned()),
+        }
+    });
+
+    PathGuard {
+        _guard: guard,
+    }
 }
 
-impl Drop for InsertGuard {
-    fn drop(&mut self) {
-        let key = self.key.take().unwrap();
-        match self.old_value.take() {
-            Some(value) => insert(key, value),
-            None => remove(&key),
-        };
+/// Hides an MDC entry from a specific log target in a scoped fashion.
+///
+/// The MDC itself has no notion of log targets, so the entry remains in the MDC and is visible to
+/// `get` and `iter` as usual. Instead, appenders which want to support per target visibility must
+/// opt in by checking `is_suppressed` for each entry before outputting it. The suppression is
+/// thread local, and is lifted when the returned guard falls out of scope.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("user", "sfackler");
+///
+/// let guard = log_mdc::suppress_scoped("user", "audit");
+/// assert!(log_mdc::is_suppressed("user", "audit"));
+/// assert!(!log_mdc::is_suppressed("user", "app"));
+///
+/// drop(guard);
+/// assert!(!log_mdc::is_suppressed("user", "audit"));
+/// ```
+pub fn suppress_scoped(key: &str, target: &str) -> SuppressGuard {
+    SUPPRESSED.with(|s| {
+        *s.borrow_mut()
+            .entry(target.to_owned())
+            .or_default()
+            .entry(key.to_owned())
+            .or_insert(0) += 1
+    });
+
+    SuppressGuard {
+        key: key.to_owned(),
+        target: target.to_owned(),
     }
 }
 
-/// A guard objects which restores MDC entries when dropped.
-pub struct ExtendGuard(Vec<(String, Option<String>)>);
+/// Determines if an MDC entry is currently hidden from a log target by `suppress_scoped`.
+pub fn is_suppressed(key: &str, target: &str) -> bool {
+    SUPPRESSED.with(|s| s.borrow().get(target).is_some_and(|keys| keys.contains_key(key)))
+}
 
-impl Drop for ExtendGuard {
-    fn drop(&mut self) {
-        MDC.with(|m| {
-            let mut m = m.borrow_mut();
-
-            for (key, value) in self.0.drain(..) {
-                match value {
-                    Some(value) => m.insert(key, value),
-                    None => m.remove(&key),
-                };
-            }
-        })
+/// Increments an integer entry in the MDC, returning a guard which decrements it when dropped.
+///
+/// This is intended for gauges such as the number of operations in flight. Unlike the other scoped
+/// functions, the guard does not restore the old value of the entry, but instead subtracts one
+/// from its value at the time the guard is dropped, so overlapping scopes on the same key
+/// accumulate correctly regardless of the order in which they end. The entry is left in the MDC
+/// once every guard has been dropped. A missing entry, or one whose value cannot be parsed as an
+/// `i64`, is treated as 0.
+///
+/// # Examples
+///
+/// ```
+/// let a = log_mdc::gauge_scope("inflight");
+/// let b = log_mdc::gauge_scope("inflight");
+/// log_mdc::get("inflight", |v| assert_eq!(Some("2"), v));
+///
+/// drop(a);
+/// log_mdc::get("inflight", |v| assert_eq!(Some("1"), v));
+///
+/// drop(b);
+/// log_mdc::get("inflight", |v| assert_eq!(Some("0"), v));
+/// ```
+pub fn gauge_scope(key: &str) -> GaugeGuard {
+    adjust(key, 1);
+    GaugeGuard(key.to_owned())
+}
+
+fn adjust(key: &str, delta: i64) {
+    with_mut(|m| {
+        // remove the old value rather than overwriting it so multimap slots don't grow
+        let value = m.remove(key)
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(0)
+            .wrapping_add(delta);
+        m.insert(key.to_owned(), value.to_string());
+    })
+}
+
+/// Invokes the provided closure with an entry temporarily inserted into the MDC.
+///
+/// This is intended to wrap a single logging macro invocation, so that the entry is present while
+/// the macro is evaluated and is restored to its old value afterwards.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate log;
+/// extern crate log_mdc;
+///
+/// fn main() {
+///     log_mdc::logging_with("user", "sfackler", || info!("logged in"));
+///     log_mdc::get("user", |v| assert_eq!(None, v));
+/// }
+/// ```
+pub fn logging_with<K, V, F, R>(key: K, value: V, f: F) -> R
+    where K: Into<String>,
+          V: Into<String>,
+          F: FnOnce() -> R
+{
+    let _guard = insert_scoped(key, value);
+    f()
+}
+
+/// Inserts a new entry into the MDC in a scoped fashion, returning a token which ends the scope.
+///
+/// This is a manual alternative to `insert_scoped` for contexts in which a guard can't be held,
+/// such as across an FFI boundary. Passing the token to `span_end` restores the old value of the
+/// key, exactly as dropping the guard returned by `insert_scoped` would. Like guards, spans should
+/// be ended in the reverse order they were begun. The tokens are only meaningful on the thread
+/// which created them, and a span which is never ended leaves its entry in the MDC.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// let token = log_mdc::span_begin("foo", "b");
+/// log_mdc::get("foo", |v| assert_eq!(Some("b"), v));
+///
+/// log_mdc::span_end(token);
+/// log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
+/// ```
+pub fn span_begin(key: &str, value: &str) -> u64 {
+    let old_value = with_mut(|m| m.insert_slot(key.to_owned(), value.to_owned()));
+    track_scoped(key);
+
+    let token = NEXT_SPAN.with(|n| {
+        let token = n.get();
+        n.set(token + 1);
+        token
+    });
+    SPANS.with(|s| s.borrow_mut().insert(token, (key.to_owned(), old_value)));
+    token
+}
+
+/// Ends a scope begun by `span_begin`, restoring the old value of its key.
+///
+/// Tokens which have already been ended, or which were not returned by `span_begin` on this
+/// thread, are ignored.
+pub fn span_end(token: u64) {
+    if let Some((key, old_value)) = SPANS.with(|s| s.borrow_mut().remove(&token)) {
+        untrack_scoped(&key);
+        with_mut(|m| m.restore_slot(key, old_value));
+    }
+}
+
+/// Extends the MDC with new entries.
+///
+/// Entries whose keys are rejected by the validator installed with `set_key_validator` are
+/// skipped.
+pub fn extend<K, V, I>(entries: I)
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    // validate before borrowing the MDC, since rejections may be logged
+    let entries = entries.into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .filter(|(k, _)| check_key(k))
+        .collect::<Vec<_>>();
+    with_mut(|m| m.extend(entries));
+}
+
+/// Extends the MDC with new entries, returning the conflicts between them and existing values.
+///
+/// Entries are applied in order, exactly as with `extend`, so the last value for a key wins. A
+/// conflict is reported as a `(key, dropped_value, kept_value)` tuple each time an entry replaces
+/// a different value for its key, whether that value was already in the MDC or came from an
+/// earlier entry in `entries`. The dropped value is the one replaced, and the kept value is the
+/// one replacing it. Entries which replace an identical value are not conflicts. Entries whose
+/// keys are rejected by the validator installed with `set_key_validator` are skipped.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("region", "us-east");
+///
+/// let conflicts = log_mdc::extend_reporting(vec![
+///     ("region", "us-east"),
+///     ("user", "sfackler"),
+///     ("user", "alex"),
+/// ]);
+///
+/// let expected = vec![("user".to_owned(), "sfackler".to_owned(), "alex".to_owned())];
+/// assert_eq!(expected, conflicts);
+/// log_mdc::get("user", |v| assert_eq!(Some("alex"), v));
+/// ```
+pub fn extend_reporting<K, V, I>(entries: I) -> Vec<(String, String, String)>
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    // validate before borrowing the MDC, since rejections may be logged
+    let entries = entries.into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .filter(|(k, _)| check_key(k))
+        .collect::<Vec<(String, String)>>();
+    with_mut(|m| {
+        let mut conflicts = vec![];
+        for (key, value) in entries {
+            if let Some(old_value) = m.insert(key.clone(), value.clone()) {
+                if old_value != value {
+                    conflicts.push((key, old_value, value));
+                }
+            }
+        }
+        conflicts
+    })
+}
+
+/// Applies a batch of changes to the MDC atomically.
+///
+/// The closure is invoked with a copy of the entries in the MDC, and the MDC is replaced with the
+/// modified copy once it returns, so no intermediate state is ever observable through `get` or
+/// `iter`, even from code such as a `Drop` implementation which logs while the changes are being
+/// made. If the closure panics, the MDC is left unchanged. This trades a copy of the entire MDC
+/// for atomic visibility of the changes.
+///
+/// With the `multimap` Cargo feature, the copy holds only the most recent value of each key, and
+/// every key of the replaced MDC holds a single value, so older values are discarded.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// log_mdc::apply_atomic(|m| {
+///     m.insert("bar".to_owned(), "b".to_owned());
+///     m.remove("foo");
+///     log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
+/// });
+///
+/// log_mdc::get("foo", |v| assert_eq!(None, v));
+/// log_mdc::get("bar", |v| assert_eq!(Some("b"), v));
+/// ```
+pub fn apply_atomic<F>(f: F)
+    where F: FnOnce(&mut HashMap<String, String>)
+{
+    let mut entries = with_mdc(|m| {
+        m.borrow()
+            .iter_latest()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<HashMap<_, _>>()
+    });
+    f(&mut entries);
+
+    let mut map = Map::with_capacity(entries.len());
+    map.extend(entries);
+    with_mut(|m| *m = map);
+}
+
+/// Extends the MDC with new entries in a scoped fashion.
+///
+/// When the returned guard falls out of scope, it will restore the old values corresponding to the
+/// keys.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// let entries = [
+///     ("foo", "b"),
+///     ("fizz", "buzz"),
+/// ];
+///
+/// let guard = log_mdc::extend_scoped(entries.iter().cloned());
+/// log_mdc::get("foo", |v| assert_eq!(Some("b"), v));
+/// log_mdc::get("fizz", |v| assert_eq!(Some("buzz"), v));
+///
+/// drop(guard);
+/// log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
+/// log_mdc::get("fizz", |v| assert_eq!(None, v));
+/// ```
+///
+/// If a key appears multiple times in the entries, the last value wins, and the value from before
+/// the call is restored:
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// let entries = vec![("foo", "b"), ("foo", "c"), ("bar", "d"), ("bar", "e")];
+///
+/// let guard = log_mdc::extend_scoped(entries);
+/// log_mdc::get("foo", |v| assert_eq!(Some("c"), v));
+/// log_mdc::get("bar", |v| assert_eq!(Some("e"), v));
+///
+/// drop(guard);
+/// log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
+/// log_mdc::get("bar", |v| assert_eq!(None, v));
+/// ```
+///
+/// Overlapping guards on shared keys restore the value from before the outermost call once they
+/// have all been dropped in the reverse order of their creation:
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// let outer = log_mdc::extend_scoped(vec![("foo", "b"), ("bar", "c")]);
+/// let inner = log_mdc::extend_scoped(vec![("bar", "d"), ("foo", "e")]);
+/// log_mdc::get("foo", |v| assert_eq!(Some("e"), v));
+///
+/// drop(inner);
+/// log_mdc::get("foo", |v| assert_eq!(Some("b"), v));
+/// log_mdc::get("bar", |v| assert_eq!(Some("c"), v));
+///
+/// drop(outer);
+/// log_mdc::get("foo", |v| assert_eq!(Some("a"), v));
+/// log_mdc::get("bar", |v| assert_eq!(None, v));
+/// ```
+pub fn extend_scoped<K, V, I>(entries: I) -> ExtendGuard
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    with_mut(|m| {
+        let mut seen = HashSet::new();
+        let mut old_entries = vec![];
+        for (k, v) in entries {
+            let k = k.into();
+            let v = m.insert_slot(k.clone(), v.into());
+            // only the first insertion of a key sees its original value
+            if seen.insert(k.clone()) {
+                old_entries.push((k, v));
+            }
+        }
+
+        ExtendGuard::new(old_entries)
+    })
+}
+
+/// Extends the MDC with new entries which are kept only if the returned guard is committed.
+///
+/// When the returned guard falls out of scope, it will restore the old values corresponding to the
+/// keys, unless `ConditionalGuard::keep` has been called, in which case the new entries remain.
+///
+/// # Examples
+///
+/// ```
+/// fn handle(fail: bool) -> Result<(), ()> {
+///     let guard = log_mdc::extend_scoped_conditional(vec![("user", "sfackler")]);
+///     if fail {
+///         return Err(());
+///     }
+///     guard.keep();
+///     Ok(())
+/// }
+///
+/// handle(true).unwrap_err();
+/// log_mdc::get("user", |v| assert_eq!(None, v));
+///
+/// handle(false).unwrap();
+/// log_mdc::get("user", |v| assert_eq!(Some("sfackler"), v));
+/// ```
+pub fn extend_scoped_conditional<K, V, I>(entries: I) -> ConditionalGuard
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    ConditionalGuard(extend_scoped(entries))
+}
+
+/// Extends the MDC with new entries in a scoped fashion, checking that none of the keys are
+/// currently scoped.
+///
+/// This behaves like `extend_scoped`, but in debug builds it panics if any of the keys is held by
+/// an outstanding guard on this thread, such as one returned by `insert_scoped` or `extend_scoped`.
+/// Overlapping scopes restore correctly, but the result depends on the order in which the guards
+/// are dropped, so an overlap is often a sign of an accidentally reused key.
+///
+/// # Examples
+///
+/// ```should_panic
+/// let _outer = log_mdc::insert_scoped("user", "sfackler");
+///
+/// // panics since `user` is held by `_outer`
+/// let _inner = log_mdc::extend_scoped_checked(vec![("user", "root")]);
+/// ```
+pub fn extend_scoped_checked<K, V, I>(entries: I) -> ExtendGuard
+    where K: Into<String>,
+          V: Into<String>,
+          I: IntoIterator<Item = (K, V)>
+{
+    let entries = entries.into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect::<Vec<(String, String)>>();
+
+    if cfg!(debug_assertions) {
+        for (key, _) in &entries {
+            assert!(!is_scoped(key),
+                    "MDC key `{}` is already held by an outstanding guard",
+                    key);
+        }
+    }
+
+    extend_scoped(entries)
+}
+
+/// Invokes the provided closure with two layers of entries temporarily added to the MDC.
+///
+/// The MDC is extended with `base` and then `overrides`, so entries in `overrides` take precedence.
+/// Both layers are recorded in a single guard, so a key present in both is restored to its
+/// original value once the closure returns.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("user", "anonymous");
+///
+/// let defaults = vec![("user", "unknown"), ("region", "us-east")];
+/// let request = vec![("user", "sfackler")];
+///
+/// log_mdc::scope_layered(defaults, request, || {
+///     log_mdc::get("user", |v| assert_eq!(Some("sfackler"), v));
+///     log_mdc::get("region", |v| assert_eq!(Some("us-east"), v));
+/// });
+///
+/// log_mdc::get("user", |v| assert_eq!(Some("anonymous"), v));
+/// log_mdc::get("region", |v| assert_eq!(None, v));
+/// ```
+pub fn scope_layered<K, V, I1, I2, F, R>(base: I1, overrides: I2, f: F) -> R
+    where K: Into<String>,
+          V: Into<String>,
+          I1: IntoIterator<Item = (K, V)>,
+          I2: IntoIterator<Item = (K, V)>,
+          F: FnOnce() -> R
+{
+    let _guard = extend_scoped(base.into_iter().chain(overrides));
+    f()
+}
+
+/// Replaces the MDC with a new, empty map in a scoped fashion.
+///
+/// The MDC can be freely modified while the returned guard is alive. When the guard falls out of
+/// scope, those modifications are discarded and the original contents of the MDC are restored.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("request", "1234");
+///
+/// let guard = log_mdc::isolated_scope();
+/// log_mdc::get("request", |v| assert_eq!(None, v));
+/// log_mdc::insert("task", "cleanup");
+///
+/// drop(guard);
+/// log_mdc::get("request", |v| assert_eq!(Some("1234"), v));
+/// log_mdc::get("task", |v| assert_eq!(None, v));
+/// ```
+pub fn isolated_scope() -> IsolatedGuard {
+    let old_map = with_mut(mem::take);
+    IsolatedGuard(Some(old_map))
+}
+
+/// Retrieves a value from the MDC.
+pub fn get<Q, F, T>(key: &Q, f: F) -> T
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq,
+          F: FnOnce(Option<&str>) -> T
+{
+    with_mdc(|m| f(m.borrow().get(key)))
+}
+
+/// Retrieves every value for a key from the MDC, oldest first.
+///
+/// Requires the `multimap` Cargo feature. In this mode, `insert` appends a value to a key rather
+/// than replacing its existing values, `get` returns the most recent value, `remove` removes every
+/// value, and `iter` visits each value separately.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("tag", "a");
+/// log_mdc::insert("tag", "b");
+///
+/// log_mdc::get_all("tag", |v| assert_eq!(["a", "b"], v));
+/// log_mdc::get("tag", |v| assert_eq!(Some("b"), v));
+///
+/// log_mdc::remove("tag");
+/// log_mdc::get_all("tag", |v| assert!(v.is_empty()));
+/// ```
+///
+/// Scoped modifications such as `push_path` replace every value of the key instead:
+///
+/// ```
+/// let _handler = log_mdc::push_path("handler");
+/// let _auth = log_mdc::push_path("auth");
+///
+/// log_mdc::get_all(log_mdc::PATH_KEY, |v| assert_eq!(["handler.auth"], v));
+/// ```
+#[cfg(feature = "multimap")]
+pub fn get_all<Q, F, T>(key: &Q, f: F) -> T
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq,
+          F: FnOnce(&[String]) -> T
+{
+    with_mdc(|m| f(m.borrow().get_all(key)))
+}
+
+/// Determines if any entry in the MDC has the specified value.
+///
+/// This scans every entry, so it takes time linear in the size of the MDC. It is mostly useful for
+/// tests and diagnostics.
+pub fn contains_value(value: &str) -> bool {
+    with_mdc(|m| m.borrow().iter().any(|(_, v)| v == value))
+}
+
+/// Removes a value from the MDC.
+pub fn remove<Q>(key: &Q) -> Option<String>
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    with_mut(|m| m.remove(key))
+}
+
+/// Removes a value from the MDC, returning whether it was present.
+pub fn remove_bool<Q>(key: &Q) -> bool
+    where String: Borrow<Q>,
+          Q: ?Sized + Hash + Eq
+{
+    remove(key).is_some()
+}
+
+/// Removes all values from the MDC, other than those of sticky keys.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::set_sticky_keys(&["service"]);
+/// log_mdc::insert("service", "web");
+/// log_mdc::insert("request", "1234");
+///
+/// log_mdc::clear();
+/// log_mdc::get("service", |v| assert_eq!(Some("web"), v));
+/// log_mdc::get("request", |v| assert_eq!(None, v));
+///
+/// log_mdc::clear_all();
+/// log_mdc::get("service", |v| assert_eq!(None, v));
+/// ```
+pub fn clear() {
+    STICKY_KEYS.with(|s| {
+        let s = s.borrow();
+        if s.is_empty() {
+            with_mut(|m| m.clear())
+        } else {
+            with_mut(|m| m.retain(|k| s.contains(k)))
+        }
+    })
+}
+
+/// Removes all values from the MDC, including those of sticky keys.
+pub fn clear_all() {
+    with_mut(|m| m.clear())
+}
+
+/// Sets the keys which are retained by `clear`, replacing any previously set.
+///
+/// This is useful for entries which are constant over the lifetime of a thread, such as the name
+/// of the service, which would otherwise need to be inserted again after each request clears the
+/// MDC. Like the MDC itself, the set of sticky keys is thread local, so it must be configured on
+/// each thread which needs it.
+pub fn set_sticky_keys(keys: &[&str]) {
+    STICKY_KEYS.with(|s| *s.borrow_mut() = keys.iter().map(|&k| k.to_owned()).collect());
+}
+
+/// Returns the capacity of the map backing the MDC.
+pub fn capacity() -> usize {
+    with_mdc(|m| m.borrow().capacity())
+}
+
+/// Invokes the provided closure for each entry in the MDC.
+pub fn iter<F>(mut f: F)
+    where F: FnMut(&str, &str)
+{
+    with_mdc(|m| {
+        for (key, value) in m.borrow().iter() {
+            f(key, value)
+        }
+    })
+}
+
+/// Invokes the provided closure with all of the entries in the MDC at once.
+///
+/// Unlike `iter`, which visits the entries one at a time, this allows the closure to index, sort,
+/// or cross-reference the entries without copying them. The MDC is borrowed while the closure
+/// runs, so the closure must not modify it.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("b", "2");
+/// log_mdc::insert("a", "1");
+///
+/// let line = log_mdc::with_all(|entries| {
+///     let mut entries = entries.to_vec();
+///     entries.sort();
+///     entries.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" ")
+/// });
+/// assert_eq!("a=1 b=2", line);
+/// ```
+pub fn with_all<F, R>(f: F) -> R
+    where F: FnOnce(&[(&str, &str)]) -> R
+{
+    with_mdc(|m| {
+        let m = m.borrow();
+        let entries = m.iter().collect::<Vec<_>>();
+        f(&entries)
+    })
+}
+
+/// Invokes the provided closure for each entry in the MDC, along with whether the entry's key is
+/// currently held by an outstanding scope guard.
+///
+/// An entry which is not held by a guard was inserted permanently and will remain in the MDC until
+/// it is explicitly removed. Note that once every guard holding a key has been dropped, the key
+/// may still be present if it had a permanent value before the first guard was created.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("service", "web");
+/// let _guard = log_mdc::insert_scoped("request", "1234");
+///
+/// log_mdc::iter_annotated(|k, _, scoped| assert_eq!(k == "request", scoped));
+/// ```
+pub fn iter_annotated<F>(mut f: F)
+    where F: FnMut(&str, &str, bool)
+{
+    with_mdc(|m| {
+        for (key, value) in m.borrow().iter() {
+            f(key, value, is_scoped(key))
+        }
+    })
+}
+
+/// Invokes the provided closure for each entry in the MDC, allowing the closure to modify the MDC.
+///
+/// The MDC is borrowed while `iter` runs its closure, so modifying the MDC from within it (for
+/// example, from a logger invoked by the closure) will panic. This function instead copies the
+/// entries before invoking the closure, at the cost of an allocation per entry.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+///
+/// log_mdc::iter_reentrant(|k, v| {
+///     log_mdc::insert(format!("{}.copy", k), v);
+/// });
+///
+/// log_mdc::get("foo.copy", |v| assert_eq!(Some("a"), v));
+/// ```
+pub fn iter_reentrant<F>(mut f: F)
+    where F: FnMut(&str, &str)
+{
+    let entries = with_mdc(|m| {
+        m.borrow().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect::<Vec<_>>()
+    });
+
+    for (key, value) in &entries {
+        f(key, value)
+    }
+}
+
+/// Invokes the provided closure for each entry in the MDC, removing the entries for which it
+/// returns `VisitAction::Remove`.
+///
+/// Removals are applied after every entry has been visited. The MDC is borrowed while the closure
+/// runs, so the closure must not access it.
+///
+/// # Examples
+///
+/// ```
+/// use log_mdc::VisitAction;
+///
+/// log_mdc::insert("request", "1234");
+/// log_mdc::insert("temp.a", "a");
+/// log_mdc::insert("temp.b", "b");
+///
+/// let mut visited = 0;
+/// log_mdc::visit_prune(|k, _| {
+///     visited += 1;
+///     if k.starts_with("temp.") {
+///         VisitAction::Remove
+///     } else {
+///         VisitAction::Keep
+///     }
+/// });
+///
+/// assert_eq!(3, visited);
+/// log_mdc::get("request", |v| assert_eq!(Some("1234"), v));
+/// log_mdc::get("temp.a", |v| assert_eq!(None, v));
+/// ```
+pub fn visit_prune<F>(mut f: F)
+    where F: FnMut(&str, &str) -> VisitAction
+{
+    with_mut(|m| {
+        let removed = m.iter()
+            .filter(|&(k, v)| f(k, v) == VisitAction::Remove)
+            .map(|(k, _)| k.to_owned())
+            .collect::<Vec<_>>();
+
+        for key in removed {
+            m.remove(&*key);
+        }
+    })
+}
+
+/// Returns a copy of the entries in the MDC, sorted by key.
+///
+/// Unlike the iteration order of the MDC, the order of the returned entries is deterministic,
+/// which makes it suitable for serialization and fingerprinting.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("b", "2");
+/// log_mdc::insert("c", "3");
+/// log_mdc::insert("a", "1");
+///
+/// let expected = vec![
+///     ("a".to_owned(), "1".to_owned()),
+///     ("b".to_owned(), "2".to_owned()),
+///     ("c".to_owned(), "3".to_owned()),
+/// ];
+/// assert_eq!(expected, log_mdc::snapshot_sorted());
+/// ```
+pub fn snapshot_sorted() -> Vec<(String, String)> {
+    let mut entries = with_mdc(|m| {
+        m.borrow().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect::<Vec<_>>()
+    });
+    entries.sort();
+    entries
+}
+
+/// Returns a copy of the entries in the MDC which stores each distinct value only once.
+///
+/// This reduces the memory used by snapshots which are forwarded to many tasks when many keys
+/// share the same value, such as a set of fields all tagged with the same deployment ID. When the
+/// values are mostly distinct, it is slower and no smaller than a plain copy.
+///
+/// Requires the `intern` Cargo feature.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// log_mdc::insert("deployment", "d-1234");
+/// log_mdc::insert("release", "d-1234");
+///
+/// let snapshot = log_mdc::snapshot_interned();
+/// thread::spawn(move || {
+///     log_mdc::extend(&snapshot);
+///     log_mdc::get("release", |v| assert_eq!(Some("d-1234"), v));
+/// }).join().unwrap();
+/// ```
+#[cfg(feature = "intern")]
+pub fn snapshot_interned() -> InternedSnapshot {
+    with_mdc(|m| {
+        let m = m.borrow();
+        let mut snapshot = InternedSnapshot {
+            values: vec![],
+            entries: vec![],
+        };
+        let mut indices = HashMap::new();
+
+        for (key, value) in m.iter() {
+            let index = *indices.entry(value).or_insert_with(|| {
+                snapshot.values.push(value.to_owned());
+                snapshot.values.len() - 1
+            });
+            snapshot.entries.push((key.to_owned(), index));
+        }
+
+        snapshot
+    })
+}
+
+/// Returns copies of the keys and values in the MDC as two parallel vectors.
+///
+/// The value at each index of the second vector corresponds to the key at the same index of the
+/// first.
+///
+/// # Examples
+///
+/// ```
+/// log_mdc::insert("foo", "a");
+/// log_mdc::insert("bar", "b");
+///
+/// let (keys, values) = log_mdc::snapshot_columns();
+/// for (key, value) in keys.iter().zip(&values) {
+///     log_mdc::get(key, |v| assert_eq!(Some(&**value), v));
+/// }
+/// ```
+pub fn snapshot_columns() -> (Vec<String>, Vec<String>) {
+    with_mdc(|m| m.borrow().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).unzip())
+}
+
+/// Replaces the contents of `dst` with a copy of the entries in the MDC.
+///
+/// `dst` is cleared first, so its allocation can be reused when repeatedly snapshotting the MDC.
+pub fn snapshot_into(dst: &mut HashMap<String, String>) {
+    dst.clear();
+    with_mdc(|m| dst.extend(m.borrow().iter().map(|(k, v)| (k.to_owned(), v.to_owned()))));
+}
+
+/// Encodes the entries in the MDC in a compact binary format.
+///
+/// The encoding consists of the number of entries, followed by each entry as the length of its key,
+/// the bytes of the key, the length of its value, and the bytes of the value. Counts and lengths
+/// are encoded as little endian `u32`s. The entries can be decoded into an MDC with `from_bytes`.
+///
+/// # Panics
+///
+/// Panics if the MDC has more than `u32::MAX` entries, or a key or value is longer than
+/// `u32::MAX` bytes.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// log_mdc::insert("user", "sfackler");
+/// let bytes = log_mdc::to_bytes();
+///
+/// thread::spawn(move || {
+///     log_mdc::from_bytes(&bytes).unwrap();
+///     log_mdc::get("user", |v| assert_eq!(Some("sfackler"), v));
+///
+///     assert!(log_mdc::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+///     assert!(log_mdc::from_bytes(&[1, 0, 0, 0, 255, 255, 255, 255]).is_err());
+/// }).join().unwrap();
+/// ```
+pub fn to_bytes() -> Vec<u8> {
+    fn push_len(len: usize, out: &mut Vec<u8>) {
+        assert!(len <= u32::MAX as usize, "length does not fit in a u32");
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+
+    with_mdc(|m| {
+        let m = m.borrow();
+        let entries = m.iter().collect::<Vec<_>>();
+
+        let mut out = vec![];
+        push_len(entries.len(), &mut out);
+        for (key, value) in entries {
+            push_len(key.len(), &mut out);
+            out.extend_from_slice(key.as_bytes());
+            push_len(value.len(), &mut out);
+            out.extend_from_slice(value.as_bytes());
+        }
+        out
+    })
+}
+
+/// Extends the MDC with entries in the binary format written by `to_bytes`.
+///
+/// The input is validated in its entirety before the MDC is modified, so if an error is returned
+/// the MDC is unchanged.
+pub fn from_bytes(data: &[u8]) -> Result<(), DecodeError> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+        if data.len() < len {
+            return Err(DecodeError("unexpected end of input"));
+        }
+        let (head, tail) = data.split_at(len);
+        *data = tail;
+        Ok(head)
+    }
+
+    fn take_len(data: &mut &[u8]) -> Result<usize, DecodeError> {
+        let bytes = take(data, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn take_str(data: &mut &[u8]) -> Result<String, DecodeError> {
+        let len = take_len(data)?;
+        let bytes = take(data, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError("invalid UTF-8"))
+    }
+
+    let mut data = data;
+    let count = take_len(&mut data)?;
+    // every entry takes at least 8 bytes, so don't trust larger counts for the allocation
+    let mut entries = Vec::with_capacity(count.min(data.len() / 8));
+    for _ in 0..count {
+        let key = take_str(&mut data)?;
+        let value = take_str(&mut data)?;
+        entries.push((key, value));
+    }
+
+    if !data.is_empty() {
+        return Err(DecodeError("trailing bytes after last entry"));
+    }
+
+    extend(entries);
+    Ok(())
+}
+
+/// A copy of the entries in the MDC which stores each distinct value only once.
+///
+/// Iterating over a reference to the snapshot yields its entries, so the MDC of another thread can
+/// be extended with them by passing the reference to `extend`.
+///
+/// Requires the `intern` Cargo feature.
+#[cfg(feature = "intern")]
+#[derive(Debug, Clone)]
+pub struct InternedSnapshot {
+    values: Vec<String>,
+    entries: Vec<(String, usize)>,
+}
+
+#[cfg(feature = "intern")]
+impl InternedSnapshot {
+    /// Returns an iterator over the entries of the snapshot.
+    pub fn iter(&self) -> InternedIter<'_> {
+        InternedIter {
+            snapshot: self,
+            entries: self.entries.iter(),
+        }
+    }
+
+    /// Returns the number of entries in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Determines if the snapshot has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(feature = "intern")]
+impl<'a> IntoIterator for &'a InternedSnapshot {
+    type Item = (&'a str, &'a str);
+    type IntoIter = InternedIter<'a>;
+
+    fn into_iter(self) -> InternedIter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of an `InternedSnapshot`.
+#[cfg(feature = "intern")]
+pub struct InternedIter<'a> {
+    snapshot: &'a InternedSnapshot,
+    entries: std::slice::Iter<'a, (String, usize)>,
+}
+
+#[cfg(feature = "intern")]
+impl<'a> Iterator for InternedIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.entries.next().map(|(k, i)| (&**k, &*self.snapshot.values[*i]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// The action to take on an entry visited by `visit_prune`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitAction {
+    /// Keep the entry in the MDC.
+    Keep,
+    /// Remove the entry from the MDC.
+    Remove,
+}
+
+/// A guard object which restores an MDC entry when dropped.
+pub struct InsertGuard {
+    key: Option<String>,
+    old_value: Option<Slot>,
+}
+
+impl InsertGuard {
+    fn new(key: String, old_value: Option<Slot>) -> InsertGuard {
+        track_scoped(&key);
+
+        InsertGuard {
+            key: Some(key),
+            old_value,
+        }
+    }
+}
+
+impl Drop for InsertGuard {
+    fn drop(&mut self) {
+        let key = self.key.take().unwrap();
+        untrack_scoped(&key);
+        let old_value = self.old_value.take();
+        with_mut(|m| m.restore_slot(key, old_value));
+    }
+}
+
+/// A guard objects which restores MDC entries when dropped.
+pub struct ExtendGuard(Vec<(String, Option<Slot>)>);
+
+impl ExtendGuard {
+    fn new(old_entries: Vec<(String, Option<Slot>)>) -> ExtendGuard {
+        for (key, _) in &old_entries {
+            track_scoped(key);
+        }
+
+        ExtendGuard(old_entries)
+    }
+}
+
+impl ExtendGuard {
+    // forgets the old values so nothing is restored on drop
+    fn disarm(&mut self) {
+        for (key, _) in self.0.drain(..) {
+            untrack_scoped(&key);
+        }
+    }
+}
+
+impl Drop for ExtendGuard {
+    fn drop(&mut self) {
+        // restore in reverse order of recording, so the earliest value wins if a key is ever
+        // recorded more than once
+        with_mut(|m| {
+            for (key, value) in self.0.drain(..).rev() {
+                untrack_scoped(&key);
+                m.restore_slot(key, value);
+            }
+        })
+    }
+}
+
+/// A guard object which restores MDC entries when dropped unless it has been kept.
+pub struct ConditionalGuard(ExtendGuard);
+
+impl ConditionalGuard {
+    /// Consumes the guard, keeping the new entries in the MDC.
+    pub fn keep(mut self) {
+        self.0.disarm();
+    }
+}
+
+/// A guard object which logs its lifetime and restores an MDC entry when dropped.
+#[cfg(feature = "log")]
+pub struct TimedGuard {
+    key: String,
+    start: Instant,
+    // restores the entry after the message is logged
+    _guard: InsertGuard,
+}
+
+#[cfg(feature = "log")]
+impl Drop for TimedGuard {
+    fn drop(&mut self) {
+        debug!("{} took {:?}", self.key, self.start.elapsed());
+    }
+}
+
+/// A guard object which lifts the suppression of an MDC entry for a log target when dropped.
+pub struct SuppressGuard {
+    key: String,
+    target: String,
+}
+
+impl Drop for SuppressGuard {
+    fn drop(&mut self) {
+        SUPPRESSED.with(|s| {
+            let mut s = s.borrow_mut();
+            let done = match s.get_mut(&self.target) {
+                Some(keys) => {
+                    let count = keys.get_mut(&self.key).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        keys.remove(&self.key);
+                    }
+                    keys.is_empty()
+                }
+                None => false,
+            };
+            if done {
+                s.remove(&self.target);
+            }
+        })
+    }
+}
+
+/// A guard object which restores the path in the MDC when dropped.
+pub struct PathGuard {
+    _guard: InsertGuard,
+}
+
+/// A guard object which decrements an integer MDC entry when dropped.
+pub struct GaugeGuard(String);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        adjust(&self.0, -1);
+    }
+}
+
+/// A guard object which restores the entire MDC when dropped.
+pub struct IsolatedGuard(Option<Map>);
+
+impl Drop for IsolatedGuard {
+    fn drop(&mut self) {
+        let old_map = self.0.take().unwrap();
+        with_mut(|m| *m = old_map);
     }
 }
@@ -0,0 +1,171 @@
+//! The map backing the thread local MDC.
+//!
+//! Each key maps to a slot. Normally a slot is a single value, but with the `multimap` Cargo
+//! feature enabled it holds every value inserted for the key, oldest first. Scope guards save and
+//! restore whole slots, so they behave identically in both modes.
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[cfg(not(feature = "multimap"))]
+pub type Slot = String;
+#[cfg(feature = "multimap")]
+pub type Slot = Vec<String>;
+
+/// Returns the most recent value in a slot.
+#[cfg(not(feature = "multimap"))]
+pub fn latest(slot: &Slot) -> &str {
+    slot
+}
+
+/// Returns the most recent value in a slot.
+#[cfg(feature = "multimap")]
+pub fn latest(slot: &Slot) -> &str {
+    slot.last().unwrap()
+}
+
+#[derive(Clone, Default)]
+pub struct Map(HashMap<String, Slot>);
+
+impl Map {
+    pub fn with_capacity(capacity: usize) -> Map {
+        Map(HashMap::with_capacity(capacity))
+    }
+
+    /// Inserts a value, returning the most recent old value.
+    #[cfg(not(feature = "multimap"))]
+    pub fn insert(&mut self, key: String, value: String) -> Option<String> {
+        self.0.insert(key, value)
+    }
+
+    /// Appends a value, returning the most recent old value.
+    #[cfg(feature = "multimap")]
+    pub fn insert(&mut self, key: String, value: String) -> Option<String> {
+        let values = self.0.entry(key).or_default();
+        let old_value = values.last().cloned();
+        values.push(value);
+        old_value
+    }
+
+    /// Inserts a value, returning the entire old slot.
+    #[cfg(not(feature = "multimap"))]
+    pub fn insert_slot(&mut self, key: String, value: String) -> Option<Slot> {
+        self.0.insert(key, value)
+    }
+
+    /// Appends a value, returning the entire old slot.
+    #[cfg(feature = "multimap")]
+    pub fn insert_slot(&mut self, key: String, value: String) -> Option<Slot> {
+        let values = self.0.entry(key).or_default();
+        let old_slot = if values.is_empty() {
+            None
+        } else {
+            Some(values.clone())
+        };
+        values.push(value);
+        old_slot
+    }
+
+    /// Replaces the value, returning the entire old slot.
+    #[cfg(not(feature = "multimap"))]
+    pub fn replace_slot(&mut self, key: String, value: String) -> Option<Slot> {
+        self.0.insert(key, value)
+    }
+
+    /// Replaces every value with a single one, returning the entire old slot.
+    #[cfg(feature = "multimap")]
+    pub fn replace_slot(&mut self, key: String, value: String) -> Option<Slot> {
+        self.0.insert(key, vec![value])
+    }
+
+    /// Restores a slot previously returned by `insert_slot`, `replace_slot` or `remove_slot`.
+    pub fn restore_slot(&mut self, key: String, slot: Option<Slot>) {
+        match slot {
+            Some(slot) => self.0.insert(key, slot),
+            None => self.0.remove(&key),
+        };
+    }
+
+    pub fn extend<I>(&mut self, entries: I)
+        where I: IntoIterator<Item = (String, String)>
+    {
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+    }
+
+    /// Returns the most recent value for a key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&str>
+        where String: Borrow<Q>,
+              Q: ?Sized + Hash + Eq
+    {
+        self.0.get(key).map(latest)
+    }
+
+    /// Returns every value for a key, oldest first.
+    #[cfg(feature = "multimap")]
+    pub fn get_all<Q>(&self, key: &Q) -> &[String]
+        where String: Borrow<Q>,
+              Q: ?Sized + Hash + Eq
+    {
+        self.0.get(key).map_or(&[], |v| &**v)
+    }
+
+    /// Removes every value for a key, returning the most recent one.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<String>
+        where String: Borrow<Q>,
+              Q: ?Sized + Hash + Eq
+    {
+        self.remove_slot(key).map(into_latest)
+    }
+
+    /// Removes every value for a key, returning the entire slot.
+    pub fn remove_slot<Q>(&mut self, key: &Q) -> Option<Slot>
+        where String: Borrow<Q>,
+              Q: ?Sized + Hash + Eq
+    {
+        self.0.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Removes every slot whose key doesn't satisfy the predicate.
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&str) -> bool
+    {
+        self.0.retain(|k, _| f(k))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Returns an iterator over the entries of the map, yielding each value separately.
+    #[cfg(not(feature = "multimap"))]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (&**k, &**v))
+    }
+
+    /// Returns an iterator over the entries of the map, yielding each value separately.
+    #[cfg(feature = "multimap")]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().flat_map(|(k, vs)| vs.iter().map(move |v| (&**k, &**v)))
+    }
+
+    /// Returns an iterator over the keys of the map along with the most recent value of each.
+    pub fn iter_latest(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, slot)| (&**k, latest(slot)))
+    }
+}
+
+#[cfg(not(feature = "multimap"))]
+fn into_latest(slot: Slot) -> String {
+    slot
+}
+
+#[cfg(feature = "multimap")]
+fn into_latest(mut slot: Slot) -> String {
+    slot.pop().unwrap()
+}